@@ -1,7 +1,7 @@
-use std::{io::{stdout, stdin, Write}, str::FromStr, fmt::Debug, net::SocketAddr, sync::{Arc, Mutex}};
+use std::{io::{stdout, stdin, Write, ErrorKind}, fs::OpenOptions, str::FromStr, fmt::Debug, net::SocketAddr, sync::{Arc, Mutex}, path::{Path, PathBuf}};
 use crossbeam_channel::unbounded;
 use log::error;
-use tell_lib::{net::{adapter::{Rx, AdapterConfig}, server::Server, client::Client}, err::TResult, id::Id, packet::TargetMode};
+use tell_lib::{net::{adapter::{Rx, AdapterConfig}, server::Server, client::Client, secure::{StaticKey, PublicKey}}, err::TResult, id::Id, packet::TargetMode, room::ModAction, history::{FileStore, Scope}, presence::Status, receipt::Receipt, transfer::TransferState};
 
 fn main() -> TResult {
     println!("Tell Client -- v{}", env!("CARGO_PKG_VERSION"));
@@ -14,16 +14,24 @@ fn main() -> TResult {
         server(id, port)
     } else if mode == "client" {
         let target_addr: SocketAddr = read_input("Target Address [ip:port]");
-        client(id, port, target_addr)
+        let server_key = read_line("Server Key [hex, empty for plaintext]");
+        let server_key = if server_key.is_empty() {
+            None
+        } else {
+            Some(server_key.parse()?)
+        };
+        client(id, port, target_addr, server_key)
     } else {
         panic!("Invalid mode")
     }
 }
 
 fn server(id: Id, port: u16) -> TResult {
-    let server = Server::setup(id, AdapterConfig {
-        port, max_conns: 16
-    })?;
+    let mut config = AdapterConfig::new(port, 16);
+    let static_key = StaticKey::generate();
+    println!("Server key: {}", static_key.public_key());
+    config.static_key = Some(static_key);
+    let server = Server::with_history(id, config, Box::new(FileStore::open("tell_history.log")?))?;
     let server = Arc::new(Mutex::new(server));
     let poll_server = server.clone();
     std::thread::spawn(move || {
@@ -41,9 +49,9 @@ fn server(id: Id, port: u16) -> TResult {
     }
 }
 
-fn client(id: Id, port: u16, target_addr: SocketAddr) -> TResult {
+fn client(id: Id, port: u16, target_addr: SocketAddr, server_key: Option<PublicKey>) -> TResult {
     let mut client = Client::new(id, port)?;
-    client.connect(target_addr)?;
+    client.connect(target_addr, server_key)?;
     let client = Arc::new(Mutex::new(client));
     let poll_client = client.clone();
     std::thread::spawn(move || {
@@ -54,18 +62,195 @@ fn client(id: Id, port: u16, target_addr: SocketAddr) -> TResult {
         }
     });
     loop {
-        let cmd = read_line("Cmd [msg/metrics]");
+        let cmd = read_line("Cmd [msg/whisper/who/status/join/leave/rooms/topic/say/reply/thread/edit/delete/react/unreact/kick/ban/mute/lock/unlock/more/sent/read/send/files/accept/cancel/save/metrics]");
         if cmd == "msg" {
+            client.lock().unwrap().typing(TargetMode::Broadcast)?;
             let msg = read_line("Write");
             if !msg.is_empty() {
                 client.lock().unwrap().message(TargetMode::Broadcast, msg)?;
             }
+        } else if cmd == "whisper" {
+            let name = read_line("To");
+            let target = client.lock().unwrap().peer(&name).cloned();
+            match target {
+                Some(target) => {
+                    let msg = read_line("Whisper");
+                    if !msg.is_empty() {
+                        client.lock().unwrap().whisper(target, msg)?;
+                    }
+                },
+                None => println!("No peer named {name}.")
+            }
+        } else if cmd == "who" {
+            let client = client.lock().unwrap();
+            client.request_peers()?;
+            for (id, target_mode) in client.typing_peers() {
+                println!("{:?} is writing to {:?}...", id, target_mode);
+            }
+        } else if cmd == "status" {
+            let status = match read_line("Status [online/away/busy]").as_str() {
+                "away" => Status::Away,
+                "busy" => Status::Busy,
+                _ => Status::Online
+            };
+            let text = read_line("Text [empty for none]");
+            client.lock().unwrap().set_presence(status, Some(text).filter(|text| !text.is_empty()))?;
+        } else if cmd == "join" {
+            client.lock().unwrap().join(read_line("Room"))?;
+        } else if cmd == "leave" {
+            client.lock().unwrap().leave(read_line("Room"))?;
+        } else if cmd == "rooms" {
+            client.lock().unwrap().list_rooms()?;
+        } else if cmd == "topic" {
+            let room = read_line("Room");
+            let topic = read_line("Topic");
+            client.lock().unwrap().set_topic(room, topic)?;
+        } else if cmd == "say" {
+            let room = read_line("Room");
+            client.lock().unwrap().typing(TargetMode::Room(room.clone()))?;
+            let msg = read_line("Write");
+            if !msg.is_empty() {
+                client.lock().unwrap().message(TargetMode::Room(room), msg)?;
+            }
+        } else if cmd == "reply" {
+            let room = read_line("Room [empty for global]");
+            let target_mode = if room.is_empty() {
+                TargetMode::Broadcast
+            } else {
+                TargetMode::Room(room)
+            };
+            let parent = read_input("Message #");
+            let msg = read_line("Write");
+            if !msg.is_empty() {
+                client.lock().unwrap().reply(target_mode, parent, msg)?;
+            }
+        } else if cmd == "thread" {
+            client.lock().unwrap().request_thread(read_input("Message #"))?;
+        } else if cmd == "edit" {
+            let id = read_input("Message #");
+            let msg = read_line("Write");
+            if !msg.is_empty() {
+                client.lock().unwrap().edit(id, msg)?;
+            }
+        } else if cmd == "delete" {
+            client.lock().unwrap().delete(read_input("Message #"))?;
+        } else if cmd == "react" || cmd == "unreact" {
+            let id = read_input("Message #");
+            let emoji = read_line("Emoji");
+            if cmd == "react" {
+                client.lock().unwrap().react(id, emoji)?;
+            } else {
+                client.lock().unwrap().unreact(id, emoji)?;
+            }
+        } else if cmd == "kick" || cmd == "ban" || cmd == "mute" {
+            let room = read_line("Room");
+            let name = read_line("Name");
+            let target = client.lock().unwrap().peer(&name).cloned();
+            match target {
+                Some(target) => {
+                    let action = match cmd.as_str() {
+                        "kick" => ModAction::Kick(target),
+                        "ban" => ModAction::Ban(target, read_input("Secs [0 lifts]")),
+                        _ => ModAction::Mute(target, read_input("Secs [0 lifts]"))
+                    };
+                    client.lock().unwrap().moderate(room, action)?;
+                },
+                None => println!("No peer named {name}.")
+            }
+        } else if cmd == "lock" || cmd == "unlock" {
+            let room = read_line("Room");
+            client.lock().unwrap().moderate(room, ModAction::LockTopic(cmd == "lock"))?;
+        } else if cmd == "more" {
+            let room = read_line("Room [empty for global]");
+            let scope = if room.is_empty() {
+                Scope::Global
+            } else {
+                Scope::Room(room)
+            };
+            if !client.lock().unwrap().more_history(scope)? {
+                println!("No older messages.");
+            }
+        } else if cmd == "sent" {
+            for outgoing in client.lock().unwrap().sent() {
+                let tick = match outgoing.state() {
+                    Receipt::Pending => " ",
+                    Receipt::Failed => "✗",
+                    Receipt::Queued => "◷",
+                    Receipt::Sent => "✓",
+                    Receipt::Delivered => "✓✓",
+                    Receipt::Read => "✓✓ read"
+                };
+                println!("{tick} {:?}: {}", outgoing.target_mode, outgoing.text);
+            }
+        } else if cmd == "read" {
+            client.lock().unwrap().read_all();
+        } else if cmd == "send" {
+            let name = read_line("To");
+            let target = client.lock().unwrap().peer(&name).cloned();
+            match target {
+                Some(target) => {
+                    let path = PathBuf::from(read_line("Path"));
+                    match (std::fs::read(&path), path.file_name()) {
+                        (Ok(data), Some(file_name)) => client.lock().unwrap()
+                            .offer_file(target, file_name.to_string_lossy().into_owned(), data)?,
+                        (Err(e), _) => println!("Can't read {:?}: {e}.", path),
+                        (_, None) => println!("{:?} is no file.", path)
+                    }
+                },
+                None => println!("No peer named {name}.")
+            }
+        } else if cmd == "files" {
+            let mut client = client.lock().unwrap();
+            for event in client.transfer_events() {
+                println!("{:?}", event);
+            }
+            for transfer in client.transfers() {
+                let direction = if transfer.incoming { "from" } else { "to" };
+                println!("#{} {} {direction} {:?}: {}/{}b, {:?}", transfer.id.map_or("?".to_owned(), |id| id.to_string()),
+                    transfer.file.name, transfer.peer, transfer.progress(), transfer.file.size, transfer.state);
+            }
+        } else if cmd == "accept" {
+            client.lock().unwrap().accept_file(read_input("Transfer #"))?;
+        } else if cmd == "cancel" {
+            client.lock().unwrap().cancel_transfer(read_input("Transfer #"))?;
+        } else if cmd == "save" {
+            let id = read_input("Transfer #");
+            let client = client.lock().unwrap();
+            match client.transfer(id) {
+                // Only the name, never a path the sender chose
+                Some(transfer) if transfer.incoming && transfer.state == TransferState::Completed => {
+                    let name = Path::new(&transfer.file.name).file_name().map_or("download".into(), |name| name.to_string_lossy());
+                    match save_new(Path::new(name.as_ref()), &transfer.data) {
+                        Ok(path) => println!("Saved to {:?}.", path),
+                        Err(e) => println!("Can't save {name}: {e}.")
+                    }
+                },
+                _ => println!("No received file #{id}.")
+            }
         } else if cmd == "metrics" {
             client.lock().unwrap().print_metrics();
         }
     }
 }
 
+// Never overwrites, picks "name (n).ext" if the name is taken
+fn save_new(path: &Path, data: &[u8]) -> std::io::Result<PathBuf> {
+    let stem = path.file_stem().map_or("download".into(), |stem| stem.to_string_lossy());
+    let ext = path.extension().map_or(String::new(), |ext| format!(".{}", ext.to_string_lossy()));
+    for n in 0..100 {
+        let path = match n {
+            0 => path.to_path_buf(),
+            n => PathBuf::from(format!("{stem} ({n}){ext}"))
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => return file.write_all(data).map(|_| path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e)
+        }
+    }
+    Err(ErrorKind::AlreadyExists.into())
+}
+
 pub fn read_line(input: &str) -> String {
     let mut line = String::new();
     print!("{}/: ", input);
@@ -1,31 +1,47 @@
-use std::io::Cursor;
-
-use rmp_serde::{Serializer, Deserializer};
-use serde::{Serialize, Deserialize};
-use crate::{id::Id, packet::{PacketType, Packet}, err::TResult};
+use crate::{id::Id, packet::{PacketType, Packet}, header::PacketHeader, codec::CodecKind, err::{TResult, TellErr, LibErr}, net::fragment::{Fragment, UDP_MAX_FRAGMENTS}};
 
 #[derive(Clone)]
 pub struct PacketBuilder {
-    id: Id
+    id: Id,
+    codec: CodecKind
 }
 
 impl PacketBuilder {
-    pub fn new(id: Id) -> PacketBuilder {
+    pub fn new(id: Id, codec: CodecKind) -> PacketBuilder {
         PacketBuilder {
-            id
+            id, codec
         }
     }
 
     pub fn serialize(&self, packet: PacketType) -> TResult<Vec<u8>> {
-        let packet = self.gen_packet(packet);
+        self.serialize_packet(&self.gen_packet(packet))
+    }
+
+    pub fn serialize_packet(&self, packet: &Packet) -> TResult<Vec<u8>> {
         let mut buf = vec![];
-        let mut ser = Serializer::new(&mut buf);
-        packet.serialize(&mut ser)?;
+        packet.header.encode(&mut buf)?;
+        packet.header.codec().codec().encode(&packet.payload, &mut buf)?;
         Ok(buf)
     }
 
-    fn gen_packet(&self, packet: PacketType) -> Packet {
-        Packet::new(self.id.clone(), packet)
+    // Split a serialized packet into fragments that are sent as packets of their own
+    pub fn fragment(&self, id: u32, bytes: &[u8]) -> TResult<Vec<PacketType>> {
+        let size = self.codec.fragment_size();
+        let count = bytes.len().div_ceil(size);
+        if count > UDP_MAX_FRAGMENTS {
+            return Err(TellErr::Lib(LibErr::PacketTooLarge(bytes.len())))
+        }
+        Ok(bytes.chunks(size).enumerate()
+            .map(|(index, chunk)| PacketType::Fragment(Fragment {
+                id, index: index as u16, count: count as u16, bytes: chunk.to_vec()
+            }))
+            .collect())
+    }
+
+    pub fn gen_packet(&self, packet: PacketType) -> Packet {
+        let mut packet = Packet::new(self.id.clone(), packet);
+        packet.header.set_codec(self.codec);
+        packet
     }
 }
 
@@ -38,37 +54,131 @@ impl PacketReader {
         PacketReader{}
     }
 
-    pub fn deserialize(&self, buf: &mut Vec<u8>) -> TResult<Packet> {
-        let mut reader = Cursor::new(buf);
-        let mut de = Deserializer::new(&mut reader);
-        Ok(Packet::deserialize(&mut de)?)
+    // Compact headers only carry a session id, `resolve_session` maps it back to the source
+    pub fn deserialize<F: FnOnce(u32) -> Option<Id>>(&self, mut buf: &[u8], resolve_session: F) -> TResult<Packet> {
+        let header = PacketHeader::decode(&mut buf, resolve_session)?;
+        let payload = header.codec().codec().decode(buf)?;
+        Ok(Packet {
+            header, payload
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{id::Id, packet::{PacketType, ServerPacket}, builder::PacketReader};
+    use crate::{id::Id, packet::{PacketType, ServerPacket, TargetMode, Handshake, PROTOCOL_VERSION}, builder::PacketReader, codec::CodecKind, net::{fragment::Reassembler, compress::Compression, capability::Capabilities, adapter::{CHANNEL_RELIABLE, Delivery, UDP_READ_BUF_SIZE}, reliable::Ack}};
 
     use super::PacketBuilder;
 
+    fn connect_accepted() -> PacketType {
+        PacketType::Server(ServerPacket::ConnectAccepted(Handshake {
+            session: 1, version: PROTOCOL_VERSION, capabilities: Capabilities::supported(), compression: Compression::None,
+            mailbox: None
+        }))
+    }
+
     #[test]
     fn serialize() {
-        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap());
-        let packet = PacketType::Server(
-            ServerPacket::PeerConnected(Id::new("Alice".to_owned()).unwrap()));
-        let bytes = builder.serialize(packet).unwrap();
-        assert_eq!(bytes.len(), 91);
+        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap(), CodecKind::MessagePack);
+        let bytes = builder.serialize(connect_accepted()).unwrap();
+        assert_eq!(bytes.len(), 63);
     }
 
     #[test]
     fn deserialize() {
-        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap());
-        let packet = PacketType::Server(
-            ServerPacket::PeerConnected(Id::new("Alice".to_owned()).unwrap()));
-        let mut bytes = builder.serialize(packet.clone()).unwrap();
-        assert_eq!(bytes.len(), 91);
+        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap(), CodecKind::MessagePack);
+        let packet = connect_accepted();
+        let bytes = builder.serialize(packet.clone()).unwrap();
+        assert_eq!(bytes.len(), 63);
+        let reader = PacketReader::new();
+        let de_packet = reader.deserialize(&bytes, |_| None).unwrap();
+        assert_eq!(packet, de_packet.payload);
+        // Wire version
+        assert!(reader.deserialize(&[1], |_| None).is_err());
+        assert!(reader.deserialize(&bytes[..10], |_| None).is_err());
+    }
+
+    #[test]
+    fn session() {
+        let bob = Id::new("Bob".to_owned()).unwrap();
+        let builder = PacketBuilder::new(bob.clone(), CodecKind::MessagePack);
+        let mut packet = builder.gen_packet(PacketType::Heartbeat);
+        packet.header.set_channel(CHANNEL_RELIABLE, Delivery::ReliableOrdered, Some(300));
+        packet.header.set_acks(vec![(CHANNEL_RELIABLE, Ack { next: 12, bits: 0b101 })]);
+        let full = builder.serialize_packet(&packet).unwrap();
+        packet.header.set_session(Some(7));
+        let compact = builder.serialize_packet(&packet).unwrap();
+        assert_eq!(compact.len(), 21);
+        assert!(compact.len() * 2 < full.len());
+
         let reader = PacketReader::new();
-        let de_packet = reader.deserialize(&mut bytes).unwrap();
+        let de_packet = reader.deserialize(&compact, |session| {
+            (session == 7).then(|| bob.clone())
+        }).unwrap();
+        assert_eq!(packet, de_packet);
+        // Session wasn't handed out to this source
+        assert!(reader.deserialize(&compact, |_| None).is_err());
+    }
+
+    #[test]
+    fn codec() {
+        let reader = PacketReader::new();
+        let packet = connect_accepted();
+        for codec in [CodecKind::Bincode, CodecKind::Json] {
+            let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap(), codec);
+            let bytes = builder.serialize(packet.clone()).unwrap();
+            let de_packet = reader.deserialize(&bytes, |_| None).unwrap();
+            assert_eq!(de_packet.header().codec(), codec);
+            assert_eq!(de_packet.payload, packet);
+        }
+    }
+
+    #[test]
+    fn fragment() {
+        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap(), CodecKind::MessagePack);
+        let packet = PacketType::Server(ServerPacket::Message {
+            id: 0,
+            source: Id::new("Alice".to_owned()).unwrap(),
+            target_mode: TargetMode::Broadcast, text: "Hello world! ".repeat(200), parent: None
+        });
+        let bytes = builder.serialize(packet.clone()).unwrap();
+        let fragments = builder.fragment(1, &bytes).unwrap();
+        assert_eq!(fragments.len(), 6);
+
+        let mut reassembler = Reassembler::new();
+        let mut reassembled = None;
+        for fragment in fragments.into_iter().rev() {
+            if let PacketType::Fragment(fragment) = fragment {
+                reassembled = reassembler.insert(fragment).unwrap();
+            }
+        }
+        let de_packet = PacketReader::new().deserialize(&reassembled.unwrap(), |_| None).unwrap();
         assert_eq!(packet, de_packet.payload);
     }
+
+    #[test]
+    fn fragment_codecs() {
+        let packet = PacketType::Server(ServerPacket::Message {
+            id: u64::MAX,
+            source: Id::new("Alice12345".to_owned()).unwrap(),
+            target_mode: TargetMode::Broadcast, text: "\u{1F980}\"\\ ".repeat(1500), parent: None
+        });
+        for codec in [CodecKind::MessagePack, CodecKind::Bincode, CodecKind::Json] {
+            let builder = PacketBuilder::new(Id::new("Bob1234567".to_owned()).unwrap(), codec);
+            let bytes = builder.serialize(packet.clone()).unwrap();
+            let mut reassembler = Reassembler::new();
+            let mut reassembled = None;
+            for fragment in builder.fragment(u32::MAX, &bytes).unwrap().into_iter() {
+                let mut fragment_packet = builder.gen_packet(fragment.clone());
+                fragment_packet.header.set_channel(CHANNEL_RELIABLE, Delivery::ReliableOrdered, Some(u32::MAX));
+                // Room is left for the seal
+                assert!(builder.serialize_packet(&fragment_packet).unwrap().len() <= UDP_READ_BUF_SIZE * 2 - 64);
+                if let PacketType::Fragment(fragment) = fragment {
+                    reassembled = reassembler.insert(fragment).unwrap();
+                }
+            }
+            let de_packet = PacketReader::new().deserialize(&reassembled.unwrap(), |_| None).unwrap();
+            assert_eq!(packet, de_packet.payload);
+        }
+    }
 }
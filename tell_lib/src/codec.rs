@@ -0,0 +1,163 @@
+use bincode::Options;
+use rmp_serde::{Serializer, Deserializer};
+use serde::{Serialize, Deserialize};
+use crate::{packet::PacketType, err::{TResult, TellErr, LibErr}, net::fragment::{UDP_FRAGMENT_SIZE, UDP_MAX_PACKET_SIZE}};
+
+// Serializes packet payloads. The header is always encoded by hand and declares
+// which codec the payload that follows it uses.
+pub trait Codec: Send + Sync {
+    fn kind(&self) -> CodecKind;
+    fn encode(&self, payload: &PacketType, buf: &mut Vec<u8>) -> TResult;
+    fn decode(&self, bytes: &[u8]) -> TResult<PacketType>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodecKind {
+    MessagePack,
+    // Compact binary
+    Bincode,
+    // Human readable, for debugging with netcat/Wireshark
+    Json
+}
+
+impl CodecKind {
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            CodecKind::MessagePack => &MessagePackCodec,
+            CodecKind::Bincode => &BincodeCodec,
+            CodecKind::Json => &JsonCodec
+        }
+    }
+
+    pub fn flag(self) -> u8 {
+        match self {
+            CodecKind::MessagePack => 0,
+            CodecKind::Bincode => 1,
+            CodecKind::Json => 2
+        }
+    }
+
+    // Bytes per fragment, so the encoded fragment still fits into a datagram.
+    // Json writes each byte as a number of up to three digits and a comma.
+    pub fn fragment_size(self) -> usize {
+        match self {
+            CodecKind::Json => UDP_FRAGMENT_SIZE / 4,
+            _ => UDP_FRAGMENT_SIZE
+        }
+    }
+
+    pub fn from_flag(flag: u8) -> TResult<CodecKind> {
+        match flag {
+            0 => Ok(CodecKind::MessagePack),
+            1 => Ok(CodecKind::Bincode),
+            2 => Ok(CodecKind::Json),
+            _ => Err(TellErr::Lib(LibErr::InvalidCodec(flag)))
+        }
+    }
+}
+
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::MessagePack
+    }
+
+    fn encode(&self, payload: &PacketType, buf: &mut Vec<u8>) -> TResult {
+        Ok(payload.serialize(&mut Serializer::new(buf))?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> TResult<PacketType> {
+        Ok(PacketType::deserialize(&mut Deserializer::new(bytes))?)
+    }
+}
+
+pub struct BincodeCodec;
+
+impl BincodeCodec {
+    // Length prefixes are untrusted, never allocate more than a reassembled packet may be.
+    // Also holds for encoding, larger packets couldn't be sent anyway.
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new().with_limit(UDP_MAX_PACKET_SIZE as u64)
+    }
+}
+
+impl Codec for BincodeCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Bincode
+    }
+
+    fn encode(&self, payload: &PacketType, buf: &mut Vec<u8>) -> TResult {
+        Ok(Self::options().serialize_into(buf, payload)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> TResult<PacketType> {
+        Ok(Self::options().deserialize(bytes)?)
+    }
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn kind(&self) -> CodecKind {
+        CodecKind::Json
+    }
+
+    fn encode(&self, payload: &PacketType, buf: &mut Vec<u8>) -> TResult {
+        Ok(serde_json::to_writer(buf, payload)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> TResult<PacketType> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, packet::{PacketType, ServerPacket, TargetMode}, net::fragment::Fragment};
+    use super::CodecKind;
+
+    #[test]
+    fn roundtrip() {
+        let packets = [
+            PacketType::Heartbeat,
+            PacketType::Server(ServerPacket::Message {
+                id: 0,
+                source: Id::new("Alice".to_owned()).unwrap(),
+                target_mode: TargetMode::Broadcast, text: "Hello world!".to_owned(), parent: None
+            }),
+            PacketType::Fragment(Fragment {
+                id: 3, index: 1, count: 2, bytes: vec![1, 2, 3]
+            })
+        ];
+        for kind in [CodecKind::MessagePack, CodecKind::Bincode, CodecKind::Json] {
+            let codec = kind.codec();
+            assert_eq!(codec.kind(), kind);
+            assert_eq!(CodecKind::from_flag(kind.flag()).unwrap(), kind);
+            for packet in packets.iter() {
+                let mut buf = vec![];
+                codec.encode(packet, &mut buf).unwrap();
+                assert_eq!(&codec.decode(&buf).unwrap(), packet);
+            }
+            assert!(codec.decode(&[0xff, 0xff]).is_err());
+        }
+        let mut buf = vec![];
+        CodecKind::Json.codec().encode(&PacketType::Heartbeat, &mut buf).unwrap();
+        assert_eq!(buf, b"\"Heartbeat\"");
+    }
+
+    #[test]
+    fn large() {
+        // Beyond a single datagram, sent in fragments
+        let packet = PacketType::Server(ServerPacket::Message {
+            id: 0,
+            source: Id::new("Alice".to_owned()).unwrap(),
+            target_mode: TargetMode::Broadcast, text: "x".repeat(16 * 1024), parent: None
+        });
+        for kind in [CodecKind::MessagePack, CodecKind::Bincode, CodecKind::Json] {
+            let mut buf = vec![];
+            kind.codec().encode(&packet, &mut buf).unwrap();
+            assert_eq!(kind.codec().decode(&buf).unwrap(), packet);
+        }
+    }
+}
@@ -3,7 +3,7 @@ use std::{error::Error, io, net::SocketAddr, any::Any};
 use crossbeam_channel::{TrySendError, TryRecvError};
 use rmp_serde::{decode, encode};
 
-use crate::{event::UdpAdapterEvent, net::adapter::SendCommand};
+use crate::{event::UdpAdapterEvent, net::adapter::SendCommand, packet::RejectReason};
 
 pub type TResult<T = ()> = Result<T, TellErr>;
 
@@ -12,6 +12,8 @@ pub enum TellErr {
     Io(io::Error),
     Encode(encode::Error),
     Decode(decode::Error),
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
     ChannelSend(Box<dyn Any + 'static + Send + Sync>),
     ChannelRecv(TryRecvError),
     Other(Box<dyn Any + 'static + Send>)
@@ -27,6 +29,8 @@ impl fmt::Debug for TellErr {
             TellErr::Io(e) => write!(f, "{e}"),
             TellErr::Encode(e) => write!(f, "{e}"),
             TellErr::Decode(e) => write!(f, "{e}"),
+            TellErr::Bincode(e) => write!(f, "{e}"),
+            TellErr::Json(e) => write!(f, "{e}"),
             TellErr::ChannelSend(e) => write!(f, "{:?}", e),
             TellErr::ChannelRecv(e) => write!(f, "{e}"),
             TellErr::Other(e) => write!(f, "{:?}", e)
@@ -60,6 +64,18 @@ impl From<decode::Error> for TellErr {
     }
 }
 
+impl From<bincode::Error> for TellErr {
+    fn from(value: bincode::Error) -> Self {
+        TellErr::Bincode(value)
+    }
+}
+
+impl From<serde_json::Error> for TellErr {
+    fn from(value: serde_json::Error) -> Self {
+        TellErr::Json(value)
+    }
+}
+
 impl From<TrySendError<UdpAdapterEvent>> for TellErr {
     fn from(value: TrySendError<UdpAdapterEvent>) -> Self {
         TellErr::ChannelSend(Box::new(value))
@@ -94,7 +110,22 @@ pub enum LibErr {
     PeerAlreadyConnected(SocketAddr),
     PeerNotConnected(SocketAddr),
     MaxConnectionsReached(usize),
-    NotConnected
+    InvalidChannel(u8),
+    InvalidFragment(u32),
+    ReassemblyLimitReached(usize),
+    PacketTooLarge(usize),
+    InvalidCompression(u8),
+    UnsupportedWireVersion(u8),
+    InvalidHeader,
+    UnknownSession(u32),
+    InvalidCodec(u8),
+    ConnectionRejected(RejectReason),
+    InvalidKey,
+    HandshakeFailed,
+    InvalidSeal,
+    Unsealed,
+    NotConnected,
+    UnknownTransfer(u64)
 }
 
 impl fmt::Display for LibErr {
@@ -1,9 +1,23 @@
 use std::{net::SocketAddr};
-use crate::{packet::{Packet, DisconnectReason}, id::Id};
+use crate::{packet::{Packet, DisconnectReason}, id::Id, net::adapter::ChannelId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    // Datagram couldn't be decoded
+    Malformed,
+    // Fragment was invalid or exceeded the reassembly limits
+    InvalidFragment,
+    // Datagram of an encrypted connection failed to open (forged, replayed or in plaintext)
+    InvalidSeal,
+    // Header claimed another identity than the one the connection was established with
+    SpoofedSource
+}
 
 #[derive(Debug, Clone)]
 pub enum UdpAdapterEvent {
     PeerConnect(SocketAddr, Packet),
     PeerDisconnect(SocketAddr, Option<Id>, DisconnectReason),
-    Payload(SocketAddr, Packet)
+    Payload(SocketAddr, ChannelId, Packet),
+    // Includes the number of strikes the source has collected so far
+    ProtocolViolation(SocketAddr, Option<Id>, Violation, u32)
 }
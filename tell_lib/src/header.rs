@@ -1,21 +1,178 @@
+use rmp_serde::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize};
 
-use crate::{id::Id, util::timestamp};
+use crate::{id::Id, codec::CodecKind, util::{write_varint, read_varint}, err::{TResult, TellErr, LibErr}, net::{reliable::Ack, adapter::{ChannelId, Delivery, CHANNEL_UNRELIABLE}}};
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+// Version 1 serialized the whole header as MessagePack, version 2 had no codec bits
+pub const WIRE_VERSION: u8 = 3;
+
+const FLAG_SESSION: u8 = 1;
+const FLAG_SEQ: u8 = 1 << 1;
+const DELIVERY_SHIFT: u8 = 2;
+const CODEC_SHIFT: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PacketHeader {
     source: Id,
-    timestamp: u128
+    // Once both sides know it, the session id replaces the full source on the wire
+    session: Option<u32>,
+    // Microsecs since the sender's adapter started
+    timestamp: u64,
+    channel: ChannelId,
+    delivery: Delivery,
+    // How the payload is serialized
+    codec: CodecKind,
+    // Only set for reliable packets
+    seq: Option<u32>,
+    // Acks for all reliable channels that received something since the last ack
+    acks: Vec<(ChannelId, Ack)>
 }
 
 impl PacketHeader {
     pub fn new(source: Id) -> PacketHeader {
         PacketHeader {
-            source, timestamp: timestamp()
+            source, session: None, timestamp: 0, channel: CHANNEL_UNRELIABLE,
+            delivery: Delivery::Unreliable, codec: CodecKind::MessagePack, seq: None, acks: vec![]
         }
     }
 
     pub fn source(&self) -> &Id {
         &self.source
     }
+
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn channel(&self) -> ChannelId {
+        self.channel
+    }
+
+    pub fn delivery(&self) -> Delivery {
+        self.delivery
+    }
+
+    pub fn codec(&self) -> CodecKind {
+        self.codec
+    }
+
+    pub fn seq(&self) -> Option<u32> {
+        self.seq
+    }
+
+    pub fn acks(&self) -> &[(ChannelId, Ack)] {
+        &self.acks
+    }
+
+    pub fn set_session(&mut self, session: Option<u32>) {
+        self.session = session;
+    }
+
+    pub fn set_codec(&mut self, codec: CodecKind) {
+        self.codec = codec;
+    }
+
+    pub fn set_timestamp(&mut self, timestamp: u64) {
+        self.timestamp = timestamp;
+    }
+
+    pub fn set_channel(&mut self, channel: ChannelId, delivery: Delivery, seq: Option<u32>) {
+        self.channel = channel;
+        self.delivery = delivery;
+        self.seq = seq;
+    }
+
+    pub fn set_acks(&mut self, acks: Vec<(ChannelId, Ack)>) {
+        self.acks = acks;
+    }
+
+    // Layout: version, flags (session, seq, delivery, codec), session (varint) or source (MessagePack), timestamp (varint),
+    // channel, seq (varint, reliable only), ack count followed by (channel, next, bits) each.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> TResult {
+        let mut flags = (self.delivery as u8) << DELIVERY_SHIFT | self.codec.flag() << CODEC_SHIFT;
+        if self.session.is_some() {
+            flags |= FLAG_SESSION;
+        }
+        if self.seq.is_some() {
+            flags |= FLAG_SEQ;
+        }
+        buf.push(WIRE_VERSION);
+        buf.push(flags);
+        match self.session {
+            Some(session) => write_varint(buf, session as u64),
+            None => self.source.serialize(&mut Serializer::new(&mut *buf))?
+        }
+        write_varint(buf, self.timestamp);
+        buf.push(self.channel);
+        if let Some(seq) = self.seq {
+            write_varint(buf, seq as u64);
+        }
+        write_varint(buf, self.acks.len() as u64);
+        for &(channel, ack) in self.acks.iter() {
+            buf.push(channel);
+            write_varint(buf, ack.next as u64);
+            write_varint(buf, ack.bits as u64);
+        }
+        Ok(())
+    }
+
+    // Session ids are resolved to the source they were handed out to
+    pub fn decode<F: FnOnce(u32) -> Option<Id>>(buf: &mut &[u8], resolve_session: F) -> TResult<PacketHeader> {
+        let version = read_u8(buf)?;
+        if version != WIRE_VERSION {
+            return Err(TellErr::Lib(LibErr::UnsupportedWireVersion(version)))
+        }
+        let flags = read_u8(buf)?;
+        if flags >> (CODEC_SHIFT + 2) != 0 {
+            return Err(TellErr::Lib(LibErr::InvalidHeader))
+        }
+        let codec = CodecKind::from_flag(flags >> CODEC_SHIFT & 0b11)?;
+        let delivery = match flags >> DELIVERY_SHIFT & 0b11 {
+            0 => Delivery::Unreliable,
+            1 => Delivery::ReliableUnordered,
+            2 => Delivery::ReliableOrdered,
+            _ => return Err(TellErr::Lib(LibErr::InvalidHeader))
+        };
+        let (source, session) = if flags & FLAG_SESSION != 0 {
+            let session = read_u32(buf)?;
+            let source = resolve_session(session)
+                .ok_or(TellErr::Lib(LibErr::UnknownSession(session)))?;
+            (source, Some(session))
+        } else {
+            (Id::deserialize(&mut Deserializer::new(&mut *buf))?, None)
+        };
+        let timestamp = read_varint(buf)?;
+        let channel = read_u8(buf)?;
+        let seq = if flags & FLAG_SEQ != 0 {
+            Some(read_u32(buf)?)
+        } else {
+            None
+        };
+        let count = read_varint(buf)?;
+        let mut acks = vec![];
+        for _ in 0..count.min(u8::MAX as u64 + 1) {
+            let channel = read_u8(buf)?;
+            acks.push((channel, Ack {
+                next: read_u32(buf)?, bits: read_u32(buf)?
+            }));
+        }
+        Ok(PacketHeader {
+            source, session, timestamp, channel, delivery, codec, seq, acks
+        })
+    }
+}
+
+fn read_u8(buf: &mut &[u8]) -> TResult<u8> {
+    let (&byte, rest) = buf.split_first()
+        .ok_or(TellErr::Lib(LibErr::InvalidHeader))?;
+    *buf = rest;
+    Ok(byte)
+}
+
+fn read_u32(buf: &mut &[u8]) -> TResult<u32> {
+    u32::try_from(read_varint(buf)?).map_err(|_| TellErr::Lib(LibErr::InvalidHeader))
 }
@@ -0,0 +1,367 @@
+use std::{fs::{File, OpenOptions}, io::{Read, Write}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use serde::{Serialize, Deserialize};
+use crate::{id::Id, err::TResult, packet::RequestError};
+
+// Sent to a peer after it connected or joined a room
+pub const HISTORY_BACKLOG: usize = 50;
+// Max records per page
+pub const HISTORY_PAGE: usize = 50;
+// Max text bytes per page, so a page stays well below the reassembly limit
+pub const HISTORY_PAGE_BYTES: usize = 16 * 1024;
+// Different reactions per message
+pub const MAX_REACTIONS: usize = 16;
+// Bytes, enough for emoji joined from several code points
+pub const MAX_REACTION_LEN: usize = 32;
+
+// Conversation a message belongs to. Whispers and multicasts aren't kept.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    Global,
+    Room(String)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageState {
+    Sent,
+    Edited,
+    // Tombstone, the text is gone
+    Deleted
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    // In the order they reacted
+    pub ids: Vec<Id>
+}
+
+impl Reaction {
+    pub fn count(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    // Id of the message. Increases with every message of the server, across scopes.
+    pub seq: u64,
+    // Unix secs
+    pub time: u64,
+    pub scope: Scope,
+    pub source: Id,
+    pub text: String,
+    pub state: MessageState,
+    // In the order they were first used
+    pub reactions: Vec<Reaction>,
+    // Root of the thread this replies to. Replies don't show up in pages.
+    pub parent: Option<u64>,
+    // Of a root
+    pub replies: u32
+}
+
+impl Record {
+    pub fn new(seq: u64, scope: Scope, source: Id, text: String) -> Self {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        Self {
+            seq, time, scope, source, text, state: MessageState::Sent, reactions: vec![], parent: None, replies: 0
+        }
+    }
+
+    // Returns false if the peer reacted like this already
+    pub fn react(&mut self, emoji: String, id: Id) -> Result<bool, RequestError> {
+        if emoji.is_empty() || emoji.len() > MAX_REACTION_LEN
+            || emoji.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(RequestError::InvalidReaction(emoji))
+        }
+        let full = self.reactions.len() >= MAX_REACTIONS;
+        match self.reactions.iter_mut().find(|reaction| reaction.emoji == emoji) {
+            Some(reaction) if reaction.ids.contains(&id) => Ok(false),
+            Some(reaction) => {
+                reaction.ids.push(id);
+                Ok(true)
+            },
+            None if full => Err(RequestError::TooManyReactions(MAX_REACTIONS)),
+            None => {
+                self.reactions.push(Reaction {
+                    emoji, ids: vec![id]
+                });
+                Ok(true)
+            }
+        }
+    }
+
+    // Returns false if the peer didn't react like this
+    pub fn unreact(&mut self, emoji: &str, id: &Id) -> bool {
+        let reaction = match self.reactions.iter_mut().find(|reaction| reaction.emoji == emoji) {
+            Some(reaction) => reaction,
+            None => return false
+        };
+        let count = reaction.count();
+        reaction.ids.retain(|other| other != id);
+        let removed = reaction.count() < count;
+        self.reactions.retain(|reaction| reaction.count() > 0);
+        removed
+    }
+}
+
+// A message as a client keeps it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatEntry {
+    pub id: u64,
+    pub source: Id,
+    pub text: String,
+    pub state: MessageState,
+    pub reactions: Vec<Reaction>,
+    pub parent: Option<u64>,
+    pub replies: u32
+}
+
+impl From<Record> for ChatEntry {
+    fn from(record: Record) -> Self {
+        Self {
+            id: record.seq, source: record.source, text: record.text, state: record.state, reactions: record.reactions,
+            parent: record.parent, replies: record.replies
+        }
+    }
+}
+
+// Where the server keeps its messages. Swap it out with `Server::with_history`.
+pub trait HistoryStore: Send {
+    // Seqs must increase
+    fn append(&mut self, record: Record) -> TResult;
+    // Replaces the record with the same seq, after it was edited or deleted
+    fn update(&mut self, record: Record) -> TResult;
+    fn get(&self, seq: u64) -> TResult<Option<Record>>;
+    // Latest seq stored, new messages continue after it
+    fn last_seq(&self) -> Option<u64>;
+    // Up to `limit` records of the scope older than `before`, oldest first
+    fn page(&self, scope: &Scope, before: Option<u64>, limit: usize) -> TResult<Vec<Record>>;
+    // All replies to the root, oldest first
+    fn thread(&self, root: u64) -> TResult<Vec<Record>>;
+}
+
+// Lost on restart
+pub struct MemoryStore {
+    records: Vec<Record>
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            records: vec![]
+        }
+    }
+
+    // Replaces the record if its seq is known already
+    fn put(&mut self, record: Record) {
+        match self.records.binary_search_by_key(&record.seq, |record| record.seq) {
+            Ok(i) => self.records[i] = record,
+            Err(i) => self.records.insert(i, record)
+        }
+    }
+}
+
+impl HistoryStore for MemoryStore {
+    fn append(&mut self, record: Record) -> TResult {
+        self.put(record);
+        Ok(())
+    }
+
+    fn update(&mut self, record: Record) -> TResult {
+        self.put(record);
+        Ok(())
+    }
+
+    fn get(&self, seq: u64) -> TResult<Option<Record>> {
+        Ok(self.records.binary_search_by_key(&seq, |record| record.seq).ok()
+            .map(|i| self.records[i].clone()))
+    }
+
+    fn last_seq(&self) -> Option<u64> {
+        self.records.last().map(|record| record.seq)
+    }
+
+    fn page(&self, scope: &Scope, before: Option<u64>, limit: usize) -> TResult<Vec<Record>> {
+        let mut page = self.records.iter().rev()
+            .filter(|record| &record.scope == scope && record.parent.is_none()
+                && before.is_none_or(|before| record.seq < before))
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        page.reverse();
+        Ok(page)
+    }
+
+    fn thread(&self, root: u64) -> TResult<Vec<Record>> {
+        // Replies are newer than their root
+        let start = self.records.partition_point(|record| record.seq <= root);
+        Ok(self.records[start..].iter()
+            .filter(|record| record.parent == Some(root))
+            .cloned()
+            .collect())
+    }
+}
+
+// Append-only log of length prefixed MessagePack records. Updates are appended as well and
+// replace the earlier record on load. Everything is loaded on open and served from memory afterwards.
+pub struct FileStore {
+    file: File,
+    memory: MemoryStore
+}
+
+impl FileStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> TResult<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let mut memory = MemoryStore::new();
+        let mut offset = 0;
+        while let Some(len) = bytes.get(offset..offset + 4) {
+            let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+            let record = match bytes.get(offset + 4..offset + 4 + len) {
+                Some(record) => rmp_serde::from_slice::<Record>(record)?,
+                None => break
+            };
+            memory.put(record);
+            offset += 4 + len;
+        }
+        if offset < bytes.len() {
+            // Torn write of the last record, appends must start at a record boundary
+            file.set_len(offset as u64)?;
+        }
+        Ok(Self {
+            file, memory
+        })
+    }
+}
+
+impl FileStore {
+    fn write(&mut self, record: &Record) -> TResult {
+        let bytes = rmp_serde::to_vec(record)?;
+        let mut buf = (bytes.len() as u32).to_le_bytes().to_vec();
+        buf.extend(bytes);
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+impl HistoryStore for FileStore {
+    fn append(&mut self, record: Record) -> TResult {
+        self.write(&record)?;
+        self.memory.append(record)
+    }
+
+    fn update(&mut self, record: Record) -> TResult {
+        self.write(&record)?;
+        self.memory.update(record)
+    }
+
+    fn get(&self, seq: u64) -> TResult<Option<Record>> {
+        self.memory.get(seq)
+    }
+
+    fn last_seq(&self) -> Option<u64> {
+        self.memory.last_seq()
+    }
+
+    fn page(&self, scope: &Scope, before: Option<u64>, limit: usize) -> TResult<Vec<Record>> {
+        self.memory.page(scope, before, limit)
+    }
+
+    fn thread(&self, root: u64) -> TResult<Vec<Record>> {
+        self.memory.thread(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::id::Id;
+    use crate::packet::RequestError;
+    use super::{HistoryStore, MemoryStore, FileStore, Scope, Record, MessageState, MAX_REACTIONS};
+
+    #[test]
+    fn page() {
+        let mut store = MemoryStore::new();
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        for i in 0..10 {
+            store.append(Record::new(i * 2, Scope::Global, alice.clone(), format!("g{i}"))).unwrap();
+            store.append(Record::new(i * 2 + 1, Scope::Room("rust".to_owned()), alice.clone(), format!("r{i}"))).unwrap();
+        }
+        let texts = |page: Vec<super::Record>| page.into_iter().map(|record| record.text).collect::<Vec<_>>();
+        let latest = store.page(&Scope::Global, None, 3).unwrap();
+        assert_eq!(texts(latest.clone()), vec!["g7", "g8", "g9"]);
+        let older = store.page(&Scope::Global, Some(latest[0].seq), 5).unwrap();
+        assert_eq!(texts(older), vec!["g2", "g3", "g4", "g5", "g6"]);
+        assert_eq!(texts(store.page(&Scope::Room("rust".to_owned()), Some(4), 10).unwrap()), vec!["r0", "r1"]);
+        assert!(store.page(&Scope::Room("cats".to_owned()), None, 10).unwrap().is_empty());
+
+        let mut reply = Record::new(20, Scope::Global, alice.clone(), "Re: g9".to_owned());
+        reply.parent = Some(18);
+        store.append(reply).unwrap();
+        store.append(Record::new(21, Scope::Global, alice.clone(), "g10".to_owned())).unwrap();
+        assert_eq!(texts(store.page(&Scope::Global, None, 2).unwrap()), vec!["g9", "g10"]);
+        assert_eq!(texts(store.thread(18).unwrap()), vec!["Re: g9"]);
+        assert!(store.thread(16).unwrap().is_empty());
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("tell_history_{}.log", std::process::id()));
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.append(Record::new(0, Scope::Global, alice.clone(), "Hello".to_owned())).unwrap();
+            store.append(Record::new(1, Scope::Global, alice.clone(), "Wrold".to_owned())).unwrap();
+            let mut record = store.get(1).unwrap().unwrap();
+            record.text = "World".to_owned();
+            record.state = MessageState::Edited;
+            store.update(record).unwrap();
+        }
+        // Torn write
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[9, 0, 0, 0, 1]).unwrap();
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.last_seq(), Some(1));
+        store.append(Record::new(2, Scope::Global, alice.clone(), "Again".to_owned())).unwrap();
+        drop(store);
+        let store = FileStore::open(&path).unwrap();
+        let page = store.page(&Scope::Global, None, 10).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(page.iter().map(|record| record.text.as_str()).collect::<Vec<_>>(), vec!["Hello", "World", "Again"]);
+        assert_eq!(page[0].source, alice);
+        assert_eq!(page[1].state, MessageState::Edited);
+    }
+
+    #[test]
+    fn react() {
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        let bob = Id::new("Bob".to_owned()).unwrap();
+        let mut record = Record::new(0, Scope::Global, alice.clone(), "Hello".to_owned());
+        assert_eq!(record.react("👍".to_owned(), alice.clone()), Ok(true));
+        assert_eq!(record.react("👍".to_owned(), bob.clone()), Ok(true));
+        assert_eq!(record.react("👍".to_owned(), bob.clone()), Ok(false));
+        assert_eq!(record.react("🎉".to_owned(), bob.clone()), Ok(true));
+        assert_eq!(record.react("+ 1".to_owned(), bob.clone()), Err(RequestError::InvalidReaction("+ 1".to_owned())));
+        assert_eq!(record.reactions.iter().map(|reaction| (reaction.emoji.as_str(), reaction.count())).collect::<Vec<_>>(),
+            vec![("👍", 2), ("🎉", 1)]);
+
+        assert!(record.unreact("👍", &alice));
+        assert!(!record.unreact("👍", &alice));
+        assert!(record.unreact("🎉", &bob));
+        assert_eq!(record.reactions.len(), 1);
+        assert_eq!(record.reactions[0].ids, vec![bob.clone()]);
+
+        for i in 1..MAX_REACTIONS {
+            record.react(format!("r{i}"), bob.clone()).unwrap();
+        }
+        assert_eq!(record.react("🙃".to_owned(), bob.clone()), Err(RequestError::TooManyReactions(MAX_REACTIONS)));
+        // Joining an existing one is still fine
+        assert_eq!(record.react("r1".to_owned(), alice.clone()), Ok(true));
+    }
+}
@@ -16,6 +16,10 @@ impl Id {
         })
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn verify_name(name: &String) -> TResult {
         if name.len() > 10 || name.len() < 3 {
             Err(TellErr::Lib(LibErr::InvalidName(name.clone())))
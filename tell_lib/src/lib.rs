@@ -1,6 +1,13 @@
 pub mod packet;
 pub mod header;
 pub mod builder;
+pub mod codec;
+pub mod room;
+pub mod history;
+pub mod mailbox;
+pub mod presence;
+pub mod receipt;
+pub mod transfer;
 pub mod util;
 pub mod err;
 pub mod id;
@@ -10,5 +17,12 @@ pub mod net {
     pub mod conn;
     pub mod server;
     pub mod client;
+    pub mod reliable;
+    pub mod fragment;
+    pub mod congestion;
+    pub mod compress;
+    pub mod capability;
+    pub mod secure;
+    pub mod cookie;
 }
 pub mod event;
\ No newline at end of file
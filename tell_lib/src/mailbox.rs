@@ -0,0 +1,179 @@
+use std::{collections::{HashMap, VecDeque}, time::{Instant, Duration}};
+use rand_core::{OsRng, RngCore};
+use crate::{id::Id, packet::{TargetMode, RequestError}};
+
+// Messages held per offline recipient
+pub const OFFLINE_QUOTA: usize = 32;
+// Secs until a held message is dropped
+pub const OFFLINE_EXPIRY: f32 = 24. * 60. * 60.;
+pub const MAILBOX_TOKEN_SIZE: usize = 16;
+
+pub type MailboxToken = [u8; MAILBOX_TOKEN_SIZE];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Held {
+    pub id: u64,
+    pub source: Id,
+    pub target_mode: TargetMode,
+    pub text: String,
+    since: Instant
+}
+
+struct Known {
+    // Last seen connected
+    seen: Instant,
+    token: MailboxToken
+}
+
+// Holds unicast and multicast messages for identities that were connected before, until
+// they come back. Anyone can connect with a known id, its sign is no secret. So the first
+// connect of an id gets a random token, and only a connect that presents it again gets
+// what was held. The token is sent in the handshake, which is only sealed on encrypted
+// sessions: on plain ones, whoever can read the traffic can also take the messages.
+pub struct Mailboxes {
+    known: HashMap<Id, Known>,
+    boxes: HashMap<Id, VecDeque<Held>>,
+    quota: usize,
+    expiry: Duration
+}
+
+impl Mailboxes {
+    pub fn new(quota: usize, expiry: f32) -> Self {
+        Self {
+            known: HashMap::new(), boxes: HashMap::new(), quota, expiry: Duration::from_secs_f32(expiry)
+        }
+    }
+
+    // Id connected with the token it holds, if any. Returns the token if the mailbox is its own,
+    // a new one if the id is new. Others with the same id get nothing.
+    pub fn open(&mut self, id: &Id, token: Option<MailboxToken>) -> Option<MailboxToken> {
+        match self.known.get_mut(id) {
+            Some(known) if token == Some(known.token) => {
+                known.seen = Instant::now();
+                Some(known.token)
+            },
+            Some(_) => None,
+            None => {
+                let mut token = [0; MAILBOX_TOKEN_SIZE];
+                OsRng.fill_bytes(&mut token);
+                self.known.insert(id.clone(), Known {
+                    seen: Instant::now(), token
+                });
+                Some(token)
+            }
+        }
+    }
+
+    pub fn knows(&self, id: &Id) -> bool {
+        self.known.contains_key(id)
+    }
+
+    fn expire(&mut self, id: &Id) {
+        let expiry = self.expiry;
+        if let Some(held) = self.boxes.get_mut(id) {
+            held.retain(|held| held.since.elapsed() < expiry);
+            if held.is_empty() {
+                self.boxes.remove(id);
+            }
+        }
+    }
+
+    // Drop everything that ran out, and forget ids that weren't connected for as long
+    // and have nothing held for them
+    pub fn expire_all<F: Fn(&Id) -> bool>(&mut self, connected: F) {
+        let expiry = self.expiry;
+        self.boxes.retain(|_, held| {
+            held.retain(|held| held.since.elapsed() < expiry);
+            !held.is_empty()
+        });
+        let boxes = &self.boxes;
+        self.known.retain(|id, known| {
+            if connected(id) {
+                known.seen = Instant::now();
+            }
+            boxes.contains_key(id) || known.seen.elapsed() < expiry
+        });
+    }
+
+    pub fn hold(&mut self, target: &Id, id: u64, source: Id, target_mode: TargetMode, text: String) -> Result<(), RequestError> {
+        if !self.knows(target) {
+            return Err(RequestError::PeerOffline(target.clone()))
+        }
+        self.expire(target);
+        let held = self.boxes.entry(target.clone()).or_default();
+        if held.len() >= self.quota {
+            return Err(RequestError::MailboxFull(target.clone()))
+        }
+        held.push_back(Held {
+            id, source, target_mode, text, since: Instant::now()
+        });
+        Ok(())
+    }
+
+    // Everything that's still valid, oldest first
+    pub fn take(&mut self, id: &Id) -> Vec<Held> {
+        self.expire(id);
+        self.boxes.remove(id).map_or(vec![], Vec::from)
+    }
+
+    pub fn held(&self, id: &Id) -> usize {
+        self.boxes.get(id).map_or(0, |held| held.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, packet::{TargetMode, RequestError}};
+    use super::{Mailboxes, MAILBOX_TOKEN_SIZE};
+
+    #[test]
+    fn hold() {
+        let mut mailboxes = Mailboxes::new(2, 60.);
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        let bob = Id::new("Bob".to_owned()).unwrap();
+        let whisper = TargetMode::Unicast(bob.clone());
+        assert_eq!(mailboxes.hold(&bob, 0, alice.clone(), whisper.clone(), "Hi".to_owned()),
+            Err(RequestError::PeerOffline(bob.clone())));
+        let token = mailboxes.open(&bob, None);
+        assert!(token.is_some());
+        mailboxes.hold(&bob, 0, alice.clone(), whisper.clone(), "Hi".to_owned()).unwrap();
+        mailboxes.hold(&bob, 1, alice.clone(), whisper.clone(), "Still there?".to_owned()).unwrap();
+        assert_eq!(mailboxes.hold(&bob, 2, alice.clone(), whisper.clone(), "Hello?".to_owned()),
+            Err(RequestError::MailboxFull(bob.clone())));
+        // Same name, other identity
+        assert!(mailboxes.take(&Id::new("Bob".to_owned()).unwrap()).is_empty());
+        // Same id, but without the token
+        assert_eq!(mailboxes.open(&bob, None), None);
+        assert_eq!(mailboxes.open(&bob, Some([0; MAILBOX_TOKEN_SIZE])), None);
+        assert_eq!(mailboxes.open(&bob, token), token);
+        let held = mailboxes.take(&bob);
+        assert_eq!(held.iter().map(|held| held.text.as_str()).collect::<Vec<_>>(), vec!["Hi", "Still there?"]);
+        assert_eq!(mailboxes.held(&bob), 0);
+
+        let mut mailboxes = Mailboxes::new(2, 0.05);
+        mailboxes.open(&bob, None);
+        mailboxes.hold(&bob, 0, alice.clone(), whisper.clone(), "Hi".to_owned()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(mailboxes.take(&bob).is_empty());
+    }
+
+    #[test]
+    fn expire_all() {
+        let mut mailboxes = Mailboxes::new(2, 0.1);
+        let [alice, bob, carol] = ["Alice", "Bob", "Carol"].map(|name| Id::new(name.to_owned()).unwrap());
+        for id in [&alice, &bob, &carol] {
+            mailboxes.open(id, None);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        mailboxes.hold(&bob, 0, alice.clone(), TargetMode::Unicast(bob.clone()), "Hi".to_owned()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        // Carol left too long ago and has nothing held
+        mailboxes.expire_all(|id| id == &alice);
+        assert!(mailboxes.knows(&alice) && mailboxes.knows(&bob) && !mailboxes.knows(&carol));
+
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        mailboxes.expire_all(|id| id == &alice);
+        assert_eq!(mailboxes.held(&bob), 0);
+        assert!(mailboxes.knows(&alice) && !mailboxes.knows(&bob));
+    }
+}
@@ -1,21 +1,67 @@
 use std::{sync::{Arc, atomic::AtomicBool, Mutex, MutexGuard}, net::{UdpSocket, SocketAddr, SocketAddrV4, Ipv4Addr}, thread::{JoinHandle, self}, io::ErrorKind};
 use crossbeam_channel::{Receiver, Sender, unbounded};
 use log::{info, warn, error};
-use crate::{packet::{Packet, PacketType, DisconnectReason}, event::UdpAdapterEvent, err::TResult, id::Id, builder::{PacketBuilder, PacketReader}};
-use super::{shared_state::UdpSharedState, conn::Connection};
+use serde::{Serialize, Deserialize};
+use crate::{packet::{Packet, PacketType, DisconnectReason}, event::{UdpAdapterEvent, Violation}, err::{TResult, TellErr, LibErr}, id::Id, builder::{PacketBuilder, PacketReader}, codec::CodecKind};
+use super::{shared_state::UdpSharedState, conn::Connection, fragment::UDP_FRAGMENT_SIZE, compress::Compression, capability::Capabilities, secure::StaticKey};
 
 pub const UDP_READ_BUF_SIZE: usize = 508;
 pub const UDP_HEARTBEAT_INTERVAL: f32 = 1.25;
 pub const UDP_HEARTBEAT_INTERVAL_GRACE: f32 = UDP_HEARTBEAT_INTERVAL * 3.;
+// Initial retransmission timeout, until the rtt has been measured
+pub const UDP_RETRANSMIT_TIMEOUT: f32 = 0.2;
+// Max. time a received reliable packet waits for outgoing traffic to piggyback its ack
+pub const UDP_ACK_DELAY: f32 = 0.05;
+// Resends of a reliable packet until the peer counts as gone
+pub const UDP_MAX_RETRIES: u32 = 24;
+// Protocol violations until a source gets ignored (and disconnected)
+pub const UDP_MAX_STRIKES: u32 = 5;
+pub const UDP_IGNORE_DURATION: f32 = 30.;
 
 pub type AMx<T> = Arc<Mutex<T>>;
 pub type Sx<T> = Sender<T>;
 pub type Rx<T> = Receiver<T>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub type ChannelId = u8;
+
+// Channels of the default config
+pub const CHANNEL_RELIABLE: ChannelId = 0;
+pub const CHANNEL_UNRELIABLE: ChannelId = 1;
+// File chunks, so they don't hold up messages
+pub const CHANNEL_BULK: ChannelId = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AdapterConfig {
     pub port: u16,
-    pub max_conns: u16
+    pub max_conns: u16,
+    // Delivery guarantee of each channel, indexed by channel id
+    pub channels: Vec<Delivery>,
+    // Supported compression algorithms, by preference
+    pub compression: Vec<Compression>,
+    // Payload serialization. Peers must agree on it, the server rejects others.
+    pub codec: CodecKind,
+    // Features offered to (or accepted from) peers
+    pub capabilities: Capabilities,
+    // Lets clients open encrypted sessions with us (servers only)
+    pub static_key: Option<StaticKey>
+}
+
+impl AdapterConfig {
+    pub fn new(port: u16, max_conns: u16) -> Self {
+        Self {
+            port, max_conns,
+            channels: vec![Delivery::ReliableOrdered, Delivery::Unreliable, Delivery::ReliableOrdered],
+            compression: vec![Compression::Deflate],
+            codec: CodecKind::MessagePack,
+            capabilities: Capabilities::supported(),
+            static_key: None
+        }
+    }
+
+    pub fn delivery(&self, channel: ChannelId) -> TResult<Delivery> {
+        self.channels.get(channel as usize).copied()
+            .ok_or(TellErr::Lib(LibErr::InvalidChannel(channel)))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -25,8 +71,18 @@ pub enum SendMode {
     Unicast(SocketAddr)
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Delivery {
+    // Fire and forget
+    Unreliable,
+    // Acked and retransmitted, delivered as soon as it arrives
+    ReliableUnordered,
+    // Acked, retransmitted and delivered in order
+    ReliableOrdered
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct SendCommand(SendMode, PacketType);
+pub struct SendCommand(SendMode, ChannelId, PacketType);
 
 #[derive(Clone)]
 struct UdpAdapterParams {
@@ -39,6 +95,7 @@ struct UdpAdapterParams {
 
 pub struct UdpAdapter {
     pub shared_state: AMx<UdpSharedState>,
+    config: AdapterConfig,
     send_queue: Sx<SendCommand>,
     event_handle: Rx<UdpAdapterEvent>,
     pub thread_handle: JoinHandle<TResult>
@@ -52,23 +109,29 @@ impl UdpAdapter {
 
         let running = AtomicBool::new(true);
         let shared_state = Arc::new(Mutex::new(
-            UdpSharedState::new(sock, running, config)));
+            UdpSharedState::new(sock, running, config.clone())));
         
         let (send_queue, send_handle) = unbounded();
         let (event_queue, event_handle) = unbounded();
         let params = UdpAdapterParams {
             shared_state: shared_state.clone(), send_handle, event_queue,
-            builder: PacketBuilder::new(id), reader: PacketReader::new()
+            builder: PacketBuilder::new(id, config.codec), reader: PacketReader::new()
         };
 
         let thread_handle = Self::init_thread(params);
         Ok(UdpAdapter {
-            shared_state, send_queue, event_handle, thread_handle
+            shared_state, config, send_queue, event_handle, thread_handle
         })
     }
 
-    pub fn send_command(&self, send_mode: SendMode, packet: PacketType) -> TResult {
-        Ok(self.send_queue.try_send(SendCommand(send_mode, packet))?)
+    pub fn send_command(&self, send_mode: SendMode, channel: ChannelId, packet: PacketType) -> TResult {
+        // Reject unknown channels here, the adapter thread must not fail on them
+        self.config.delivery(channel)?;
+        Ok(self.send_queue.try_send(SendCommand(send_mode, channel, packet))?)
+    }
+
+    pub fn config(&self) -> &AdapterConfig {
+        &self.config
     }
 
     pub fn flush_events(&self) -> Vec<UdpAdapterEvent> {
@@ -101,63 +164,207 @@ impl UdpAdapter {
                     info!("Udp adaper thread stopped running");
                     return Ok(())
                 }
+                // Server and client poll through the same lock, give them a chance to take it
+                drop(_shared_state);
+                thread::yield_now();
             }
         });
         handle
     }
 
     fn send_packets(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>) -> TResult {
-        while let Ok(SendCommand(send_mode, packet)) = params.send_handle.try_recv() {
+        while let Ok(SendCommand(send_mode, channel, packet)) = params.send_handle.try_recv() {
+            let delivery = _shared_state.config().delivery(channel)?;
             let addrs = match send_mode {
                 SendMode::Broadcast => _shared_state.conn_addrs(),
                 SendMode::Multicast(addrs) => addrs,
                 SendMode::Unicast(addr) => vec![addr],
             };
-            Self::send_packet(params.clone(), _shared_state, addrs, packet)?;
+            Self::send_packet(params.clone(), _shared_state, addrs, channel, delivery, packet)?;
+        }
+        Self::flush_queues(params, _shared_state)
+    }
+
+    // Send whatever the congestion window and pacing of each connection allow
+    fn flush_queues(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>) -> TResult {
+        for addr in _shared_state.conn_addrs() {
+            while let Some((channel, delivery, packet)) = _shared_state.conns.get_mut(&addr)
+                .and_then(|conn| conn.dequeue()) {
+                Self::send_datagram(params.clone(), _shared_state, addr, channel, delivery, packet)?;
+            }
         }
         Ok(())
     }
 
-    fn send_packet(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addrs: Vec<SocketAddr>, packet: PacketType) -> TResult {
-        let bytes = params.builder.serialize(packet)?;
+    fn send_packet(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addrs: Vec<SocketAddr>, channel: ChannelId, delivery: Delivery, packet: PacketType) -> TResult {
+        // Packets that don't fit into a single datagram are split up and sent piece by piece
+        // One bad packet must not take down the adapter thread
+        let bytes = match params.builder.serialize(packet.clone()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Dropped outgoing packet that failed to serialize: {e}.");
+                return Ok(())
+            }
+        };
+        let packets = if bytes.len() > UDP_FRAGMENT_SIZE {
+            match params.builder.fragment(_shared_state.next_fragment_id(), &bytes) {
+                Ok(fragments) => fragments,
+                Err(e) => {
+                    error!("Dropped outgoing packet: {e}.");
+                    return Ok(())
+                }
+            }
+        } else {
+            vec![packet]
+        };
         for addr in addrs.into_iter() {
-            if let Some(conn) = _shared_state.conns.get_mut(&addr) {
-                conn.send(bytes.len());
+            for packet in packets.iter() {
+                if let Some(conn) = _shared_state.conns.get_mut(&addr) {
+                    conn.enqueue(channel, delivery, packet.clone());
+                } else {
+                    Self::send_datagram(params.clone(), _shared_state, addr, channel, delivery, packet.clone())?;
+                }
             }
-            _shared_state.sock.send_to(&bytes, addr)?;
         }
         Ok(())
     }
 
+    fn send_datagram(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addr: SocketAddr, channel: ChannelId, delivery: Delivery, packet: PacketType) -> TResult {
+        // Sequence numbers and acks are per connection, so each peer gets its own header
+        let mut packet = params.builder.gen_packet(packet);
+        let bytes = if _shared_state.conns.contains_key(&addr) {
+            packet.header.set_timestamp(_shared_state.clock());
+            let conn = _shared_state.conns.get_mut(&addr).unwrap();
+            conn.stamp(&mut packet.header, channel, delivery);
+            let bytes = conn.encode(params.builder.serialize_packet(&packet)?)?;
+            // Retransmissions are sealed again, a reused nonce would be dropped as replay
+            if let Some(seq) = packet.header().seq() {
+                conn.track(channel, seq, bytes.clone());
+            }
+            let bytes = conn.seal(bytes)?;
+            conn.send(bytes.len());
+            bytes
+        } else {
+            // No connection (yet) that could keep track of acks
+            packet.header.set_timestamp(_shared_state.clock());
+            Compression::None.encode(params.builder.serialize_packet(&packet)?)?
+        };
+        _shared_state.sock.send_to(&bytes, addr)?;
+        Ok(())
+    }
+
     fn recv_packets(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>) -> TResult {
         let mut buf = vec![0u8; UDP_READ_BUF_SIZE * 2];
         match _shared_state.sock.recv_from(&mut buf) {
             Ok((size, addr)) => {
+                if _shared_state.ignored(addr) {
+                    return Ok(())
+                }
                 // Zero bytes an issue?
-                let mut bytes = buf[0..size].to_vec();
-                let packet = params.reader.deserialize(&mut bytes)?;
-                //std::mem::drop(_shared_state);
-                Self::recv_packet(params.clone(), _shared_state, addr, size, packet)
+                let framed = match _shared_state.conns.get_mut(&addr) {
+                    Some(conn) => conn.open(&buf[0..size]),
+                    None => Ok(buf[0..size].to_vec())
+                };
+                // Session ids are only valid from the address they were handed out to
+                let conn = _shared_state.conns.get(&addr);
+                let packet = framed.and_then(|framed| Compression::decode(&framed)).and_then(|bytes| {
+                    Ok((bytes.len(), params.reader.deserialize(&bytes, |session| {
+                        conn.filter(|conn| conn.session() == Some(session))
+                            .and_then(|conn| conn.id().cloned())
+                    })?))
+                });
+                match packet {
+                    //std::mem::drop(_shared_state);
+                    Ok((raw_size, packet)) => Self::recv_packet(params.clone(), _shared_state, addr, size, raw_size, packet),
+                    // Late datagrams of a session that was closed, like chunks still underway after a reconnect
+                    Err(TellErr::Lib(LibErr::UnknownSession(session))) => {
+                        info!("Dropped datagram ({size}b) of unknown session {session} from {addr}.");
+                        Ok(())
+                    },
+                    // Anyone can send us garbage, so this must never take down the adapter
+                    Err(e) => {
+                        warn!("Recv malformed datagram ({size}b) from {addr}: {e}.");
+                        let violation = match e {
+                            TellErr::Lib(LibErr::InvalidSeal | LibErr::Unsealed) => Violation::InvalidSeal,
+                            _ => Violation::Malformed
+                        };
+                        Self::violation(params.clone(), _shared_state, addr, violation)
+                    }
+                }
             },
             // Recv buffer empty
             Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            // ICMP port unreachable of an earlier send (reported on Windows)
+            Err(e) if e.kind() == ErrorKind::ConnectionReset || e.kind() == ErrorKind::ConnectionRefused => {
+                warn!("Udp socket: {e}.");
+                Ok(())
+            },
             Err(e) => Err(e.into())
         }
     }
 
-    fn recv_packet(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addr: SocketAddr, size: usize, packet: Packet) -> TResult  {
+    fn recv_packet(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addr: SocketAddr, size: usize, raw_size: usize, packet: Packet) -> TResult  {
+        let now = _shared_state.clock();
         if let Some(conn) = _shared_state.conns.get_mut(&addr) {
-            conn.recv(size);
-            Ok(match packet.payload() {
-                PacketType::Heartbeat => (), // No need to forward this
-                _ => {
-                    info!("[Recv] {size}b from {:?}{addr}: {:?}.", packet.header().source(), packet.payload());
-                    params.event_queue.try_send(UdpAdapterEvent::Payload(addr, packet))?
-                },
-            })
+            if !conn.verify_source(packet.header()) {
+                warn!("Recv packet from {addr} claiming to be {:?}, expected {:?}.", packet.header().source(), conn.id());
+                return Self::violation(params, _shared_state, addr, Violation::SpoofedSource)
+            }
+            // Heartbeats without acks only tell us the peer is still there
+            let keepalive = matches!(packet.payload(), PacketType::Heartbeat | PacketType::HeartbeatReply(_))
+                && packet.header().acks().is_empty();
+            conn.recv(size, raw_size + 1, keepalive);
+            let mut violations = vec![];
+            let mut replies = vec![];
+            for packet in conn.accept(packet) {
+                let channel = packet.header().channel();
+                let packet = match packet.payload {
+                    PacketType::Fragment(fragment) => match conn.reassemble(fragment) {
+                        Ok(Some(bytes)) => match params.reader.deserialize(&bytes, |_| None) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                warn!("Recv malformed reassembled packet from {addr}: {e}.");
+                                violations.push(Violation::Malformed);
+                                continue
+                            }
+                        },
+                        Ok(None) => continue,
+                        Err(e) => {
+                            warn!("Dropped fragment from {addr}: {e}.");
+                            violations.push(Violation::InvalidFragment);
+                            continue
+                        }
+                    },
+                    _ => packet
+                };
+                // Reassembled packets carry their own header
+                if !conn.verify_source(packet.header()) {
+                    warn!("Recv reassembled packet from {addr} claiming to be {:?}.", packet.header().source());
+                    violations.push(Violation::SpoofedSource);
+                    continue
+                }
+                match packet.payload() {
+                    // Echo right away so the remote can measure the round trip
+                    PacketType::Heartbeat => replies.push(packet.header().timestamp()),
+                    PacketType::HeartbeatReply(sent) => conn.heartbeat_reply(*sent, now),
+                    _ => {
+                        info!("[Recv] {size}b from {:?}{addr}: {:?}.", packet.header().source(), packet.payload());
+                        params.event_queue.try_send(UdpAdapterEvent::Payload(addr, channel, packet))?
+                    },
+                }
+            }
+            for sent in replies.into_iter() {
+                Self::send_datagram(params.clone(), _shared_state, addr,
+                    CHANNEL_UNRELIABLE, Delivery::Unreliable, PacketType::HeartbeatReply(sent))?;
+            }
+            for violation in violations.into_iter() {
+                Self::violation(params.clone(), _shared_state, addr, violation)?;
+            }
+            Ok(())
         } else { // New connection?
             Ok(match packet.payload {
-                PacketType::Heartbeat => (),
+                // Fragments are only reassembled for established connections
+                PacketType::Heartbeat | PacketType::HeartbeatReply(_) | PacketType::Fragment(_) => (),
                 _ => {
                     info!("[Recv] [New] {size}b from {:?}{addr}: {:?}", packet.header().source(), packet.payload());
                     params.event_queue.try_send(UdpAdapterEvent::PeerConnect(addr, packet))?
@@ -166,24 +373,56 @@ impl UdpAdapter {
         }
     }
 
+    fn violation(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>, addr: SocketAddr, violation: Violation) -> TResult {
+        let strikes = _shared_state.strike(addr);
+        let id = _shared_state.conns.get(&addr).and_then(|conn| conn.id().cloned());
+        params.event_queue.try_send(
+            UdpAdapterEvent::ProtocolViolation(addr, id.clone(), violation, strikes))?;
+        if strikes >= UDP_MAX_STRIKES {
+            warn!("Ignoring {addr} for {UDP_IGNORE_DURATION}s after {strikes} protocol violations.");
+            if _shared_state.conns.get_mut(&addr).is_some_and(|conn| conn.close()) {
+                params.event_queue.try_send(
+                    UdpAdapterEvent::PeerDisconnect(addr, id, DisconnectReason::ProtocolViolation))?;
+            }
+        }
+        Ok(())
+    }
+
     fn maintain_conns(params: UdpAdapterParams, _shared_state: &mut MutexGuard<'_, UdpSharedState>) -> TResult {
         let mut notify_addrs = vec![];
+        let state = &mut **_shared_state;
+        state.expire_ignored();
         // Collect all connections with no outgoing traffic for long.
         // Also, shoot timeout events for all idle connections.
         // (Higher level logic, i.e., server and client can decide
         // what to do; disconnect/reconnect etc.)
-        for conn in _shared_state.conns.values_mut() {
+        for conn in state.conns.values_mut().filter(|conn| !conn.closed()) {
+            conn.expire_fragments();
+            // Resend everything that wasn't acked in time
+            for bytes in conn.expired() {
+                let bytes = conn.seal(bytes)?;
+                conn.send(bytes.len());
+                state.sock.send_to(&bytes, conn.addr())?;
+            }
             // Does connection state matter? Curr opinion: No, otherwise idle connections might get forgotten.
-            if conn.send_metrics().last_transfer.elapsed().as_secs_f32() >= UDP_HEARTBEAT_INTERVAL {
+            let last_send = conn.send_metrics().last_transfer.elapsed().as_secs_f32();
+            if last_send >= UDP_HEARTBEAT_INTERVAL || (conn.needs_ack() && last_send >= UDP_ACK_DELAY) {
                 notify_addrs.push(conn.addr());
             }
-            if conn.recv_metrics().last_transfer.elapsed().as_secs_f32() >= UDP_HEARTBEAT_INTERVAL_GRACE * 1.25 {
+            // Silent for long, or never acks what we send
+            if conn.last_heard().elapsed().as_secs_f32() >= UDP_HEARTBEAT_INTERVAL_GRACE * 1.25
+                || conn.retries() >= UDP_MAX_RETRIES {
+                conn.close();
                 params.event_queue.try_send(
                     UdpAdapterEvent::PeerDisconnect(
                             conn.addr(), conn.id().cloned(), DisconnectReason::Timeout))?;
             }
         }
-        // Send out heartbeats
-        Self::send_packet(params.clone(), _shared_state, notify_addrs, PacketType::Heartbeat)
+        // Send out heartbeats (carrying acks). They bypass the send queue, acks must not wait for the window.
+        for addr in notify_addrs.into_iter() {
+            Self::send_datagram(params.clone(), _shared_state, addr,
+                CHANNEL_UNRELIABLE, Delivery::Unreliable, PacketType::Heartbeat)?;
+        }
+        Ok(())
     }
 }
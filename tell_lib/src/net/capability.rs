@@ -0,0 +1,86 @@
+use core::fmt;
+use serde::{Serialize, Deserialize};
+
+// Optional protocol features, negotiated per connection. Kept as a bit set so peers
+// can announce features the other side doesn't know about yet.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+const NAMES: [(Capabilities, &str); 5] = [
+    (Capabilities::COMPRESSION, "Compression"),
+    (Capabilities::ENCRYPTION, "Encryption"),
+    (Capabilities::RELIABLE_CHANNELS, "ReliableChannels"),
+    (Capabilities::FRAGMENTATION, "Fragmentation"),
+    (Capabilities::ROOMS, "Rooms")
+];
+
+impl Capabilities {
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 1);
+    pub const RELIABLE_CHANNELS: Capabilities = Capabilities(1 << 2);
+    pub const FRAGMENTATION: Capabilities = Capabilities(1 << 3);
+    pub const ROOMS: Capabilities = Capabilities(1 << 4);
+
+    pub const fn empty() -> Capabilities {
+        Capabilities(0)
+    }
+
+    // Everything this build implements
+    pub const fn supported() -> Capabilities {
+        Capabilities(Self::COMPRESSION.0 | Self::ENCRYPTION.0 | Self::RELIABLE_CHANNELS.0 | Self::FRAGMENTATION.0
+            | Self::ROOMS.0)
+    }
+
+    pub const fn with(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn without(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & !other.0)
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // Features both sides agree on
+    pub const fn negotiate(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut set = f.debug_set();
+        for (capability, name) in NAMES.iter() {
+            if self.contains(*capability) {
+                set.entry(&format_args!("{name}"));
+            }
+        }
+        let unknown = NAMES.iter().fold(*self, |rest, (capability, _)| rest.without(*capability));
+        if unknown != Capabilities::empty() {
+            set.entry(&format_args!("{:#x}", unknown.0));
+        }
+        set.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Capabilities;
+
+    #[test]
+    fn negotiate() {
+        let ours = Capabilities::supported();
+        // Newer peer that knows about a feature we don't
+        let theirs = Capabilities::COMPRESSION.with(Capabilities::ROOMS).with(Capabilities(1 << 20));
+        let agreed = ours.negotiate(theirs);
+        assert!(agreed.contains(Capabilities::COMPRESSION));
+        assert!(agreed.contains(Capabilities::ROOMS));
+        assert!(!agreed.contains(Capabilities::ENCRYPTION));
+        assert!(!agreed.contains(Capabilities::FRAGMENTATION));
+        assert!(!agreed.contains(Capabilities(1 << 20)));
+        assert_eq!(format!("{:?}", agreed), "{Compression, Rooms}");
+        assert_eq!(format!("{:?}", theirs), "{Compression, Rooms, 0x100000}");
+    }
+}
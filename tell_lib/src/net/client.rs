@@ -1,35 +1,115 @@
-use std::{net::SocketAddr, collections::HashSet};
+use std::{net::SocketAddr, collections::HashMap, time::{Instant, Duration}};
 use log::{warn, info, error};
-use crate::{id::Id, err::{TResult, TellErr, LibErr}, packet::{ClientPacket, PacketType, TargetMode, Packet, ServerPacket}, event::UdpAdapterEvent, net::conn::{Connection, UdpConnection}};
-use super::adapter::{UdpAdapter, AdapterConfig, SendMode};
+use crate::{id::Id, err::{TResult, TellErr, LibErr}, packet::{ClientPacket, DisconnectReason, PacketType, TargetMode, Packet, ServerPacket, RequestError, RoomInfo, Hello, PROTOCOL_VERSION, CONNECT_PADDING}, event::UdpAdapterEvent, room::{RoomView, Role, ModAction}, history::{Scope, ChatEntry, MessageState}, presence::{Presence, Status, TYPING_INTERVAL, TYPING_TIMEOUT}, receipt::{Receipt, RECEIPT_INTERVAL, MAX_RECEIPT_BATCH}, mailbox::MailboxToken, transfer::{Transfer, TransferState, TransferEvent, Received, FILE_ACK_EVERY}, net::{conn::{Connection, UdpConnection}, capability::Capabilities, secure::{PublicKey, Initiator, SecureState}}};
+use super::adapter::{UdpAdapter, AdapterConfig, SendMode, CHANNEL_RELIABLE, CHANNEL_UNRELIABLE, CHANNEL_BULK};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Whisper {
+    // Known once the server relayed it
+    pub id: Option<u64>,
+    pub source: Id,
+    pub target: Id,
+    pub text: String,
+    // Of the target, also for whispers we got
+    pub state: Receipt
+}
+
+// Unicast or multicast message we sent
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outgoing {
+    pub id: Option<u64>,
+    pub target_mode: TargetMode,
+    pub text: String,
+    pub receipts: HashMap<Id, Receipt>
+}
+
+impl Outgoing {
+    // How far it got with every recipient
+    pub fn state(&self) -> Receipt {
+        self.receipts.values().min().copied().unwrap_or(Receipt::Pending)
+    }
+}
 
 pub struct Client {
     id: Id,
-    peers: HashSet<Id>,
-    chat_log: Vec<(Id, String)>,
+    peers: HashMap<Id, Presence>,
+    // Who's writing where, since when
+    typing: HashMap<(Id, TargetMode), Instant>,
+    // When we last told the server we're writing to a target
+    typing_sent: HashMap<TargetMode, Instant>,
+    // Whispers are kept apart from the public chat
+    whisper_log: Vec<Whisper>,
+    sent: Vec<Outgoing>,
+    // Unicast and multicast messages we got but didn't read yet
+    unread: Vec<u64>,
+    // Receipts for the next batch
+    delivered: Vec<u64>,
+    read: Vec<u64>,
+    receipts_flushed: Instant,
+    // Files we send or receive, oldest first
+    transfers: Vec<Transfer>,
+    transfer_events: Vec<TransferEvent>,
+    // Joined rooms
+    rooms: HashMap<String, RoomView>,
+    // Last list the server sent
+    room_list: Vec<RoomInfo>,
+    // Oldest record loaded of each scope that has older ones left
+    older: HashMap<Scope, u64>,
+    chat_log: Vec<ChatEntry>,
+    // Replies we've seen, by root
+    threads: HashMap<u64, Vec<ChatEntry>>,
     remote_addr: Option<SocketAddr>, // Pending connection?
-    adapter: UdpAdapter
+    // Expected static key of the server, if encrypted
+    server_key: Option<PublicKey>,
+    // Sent again with the cookie when the server asks for it
+    hello: Option<Hello>,
+    // Proves our id's mailbox is ours when we connect again
+    mailbox: Option<MailboxToken>,
+    pub(crate) adapter: UdpAdapter
 }
 
 impl Client {
     pub fn new(id: Id, port: u16) -> TResult<Self> {
-        let adapter = UdpAdapter::new(id.clone(), AdapterConfig {
-            port, max_conns: 1 // Only peer: server.
-        })?;
+        Self::with_config(id, AdapterConfig::new(
+            port, 1 // Only peer: server.
+        ))
+    }
+
+    pub fn with_config(id: Id, config: AdapterConfig) -> TResult<Self> {
+        let adapter = UdpAdapter::new(id.clone(), config)?;
         Ok(Client {
-            id, peers: HashSet::new(), chat_log: vec![], remote_addr: None, adapter
+            id, peers: HashMap::new(), typing: HashMap::new(), typing_sent: HashMap::new(), chat_log: vec![], whisper_log: vec![], sent: vec![], unread: vec![], delivered: vec![], read: vec![], receipts_flushed: Instant::now(), transfers: vec![], transfer_events: vec![], rooms: HashMap::new(), room_list: vec![], older: HashMap::new(), threads: HashMap::new(), remote_addr: None, server_key: None, hello: None, mailbox: None, adapter
         })
     }
 
-    pub fn connect(&mut self, remote_addr: SocketAddr) -> TResult {
+    // With a server key, the session is encrypted and only that server can accept it
+    pub fn connect(&mut self, remote_addr: SocketAddr, server_key: Option<PublicKey>) -> TResult {
         if let Some(addr) = self.remote_addr {
             Err(TellErr::Lib(LibErr::PeerAlreadyConnected(addr)))
         } else {
             info!("Connecting with {remote_addr}...");
-            self.adapter.send_command(SendMode::Unicast(remote_addr), 
-            PacketType::Client(ClientPacket::Connect))?;
-            self.adapter.shared_state.lock().unwrap().add_conn(UdpConnection::outgoing(remote_addr))?;
+            let config = self.adapter.config();
+            let mut conn = UdpConnection::outgoing(remote_addr);
+            let (capabilities, key) = match server_key {
+                Some(server_key) => {
+                    let initiator = Initiator::new(server_key);
+                    let key = initiator.public_key();
+                    conn.set_secure(SecureState::Initiating(initiator));
+                    (config.capabilities.with(Capabilities::ENCRYPTION), Some(key))
+                },
+                None => (config.capabilities.without(Capabilities::ENCRYPTION), None)
+            };
+            // Connection must exist before the connect packet goes out so it gets sequenced
+            self.adapter.shared_state.lock().unwrap().add_conn(conn)?;
+            let hello = Hello {
+                capabilities, compression: config.compression.clone(), key,
+                cookie: None, mailbox: self.mailbox, padding: vec![0; CONNECT_PADDING]
+            };
+            self.adapter.send_command(SendMode::Unicast(remote_addr), CHANNEL_RELIABLE,
+            PacketType::Client(ClientPacket::Connect(PROTOCOL_VERSION, hello.encode()?)))?;
+            self.hello = Some(hello);
             self.remote_addr = Some(remote_addr);
+            self.server_key = server_key;
             Ok(())
         }
     }
@@ -85,14 +165,255 @@ impl Client {
         None
     }
 
-    pub fn message(&self, target_mode: TargetMode, text: String) -> TResult {
-        self.send_packet(ClientPacket::Message(target_mode, text))
+    pub fn message(&mut self, target_mode: TargetMode, text: String) -> TResult {
+        self.send_packet(ClientPacket::Message(target_mode.clone(), text.clone(), None))?;
+        // The server answers these with their id and receipts, in order
+        if let TargetMode::Unicast(_) | TargetMode::Multicast(_) = target_mode {
+            self.sent.push(Outgoing {
+                id: None, target_mode, text, receipts: HashMap::new()
+            });
+        }
+        Ok(())
+    }
+
+    // The target must be the one of the parent. Replies to replies join the parent's thread.
+    pub fn reply(&self, target_mode: TargetMode, parent: u64, text: String) -> TResult {
+        self.send_packet(ClientPacket::Message(target_mode, text, Some(parent)))
+    }
+
+    pub fn request_thread(&self, root: u64) -> TResult {
+        self.send_packet(ClientPacket::RequestThread(root))
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn whisper(&mut self, target: Id, text: String) -> TResult {
+        self.message(TargetMode::Unicast(target.clone()), text.clone())?;
+        self.whisper_log.push(Whisper {
+            id: None, source: self.id.clone(), target, text, state: Receipt::Pending
+        });
+        Ok(())
+    }
+
+    // Target must be connected. It's relayed in chunks once the target accepted.
+    pub fn offer_file(&mut self, target: Id, name: String, data: Vec<u8>) -> TResult {
+        let transfer = Transfer::outgoing(target.clone(), name, data, self.adapter.config().codec);
+        self.send_packet(ClientPacket::Offer(target, transfer.file.clone()))?;
+        self.transfers.push(transfer);
+        Ok(())
+    }
+
+    pub fn accept_file(&mut self, id: u64) -> TResult {
+        let transfer = match self.transfers.iter_mut().find(|transfer| transfer.incoming && transfer.id == Some(id)) {
+            Some(transfer) if transfer.state == TransferState::Offered => transfer,
+            _ => return Err(TellErr::Lib(LibErr::UnknownTransfer(id)))
+        };
+        transfer.state = TransferState::Active;
+        let next = transfer.next;
+        self.send_packet(ClientPacket::Accept(id, next))?;
+        // Empty ones are done right away
+        self.settle_incoming(id)
+    }
+
+    // Also declines an offer
+    pub fn cancel_transfer(&mut self, id: u64) -> TResult {
+        match self.transfers.iter_mut().find(|transfer| transfer.id == Some(id)) {
+            Some(transfer) if matches!(transfer.state, TransferState::Offered | TransferState::Active) => {
+                transfer.state = TransferState::Cancelled;
+                self.send_packet(ClientPacket::CancelTransfer(id))
+            },
+            _ => Err(TellErr::Lib(LibErr::UnknownTransfer(id)))
+        }
+    }
+
+    pub fn transfers(&self) -> &Vec<Transfer> {
+        &self.transfers
+    }
+
+    pub fn transfer(&self, id: u64) -> Option<&Transfer> {
+        self.transfers.iter().find(|transfer| transfer.id == Some(id))
+    }
+
+    // What happened to our transfers since the last call
+    pub fn transfer_events(&mut self) -> Vec<TransferEvent> {
+        std::mem::take(&mut self.transfer_events)
+    }
+
+    pub fn request_peers(&self) -> TResult {
+        self.send_packet(ClientPacket::RequestPeers)
+    }
+
+    // Other connected peer going by this name
+    pub fn peer(&self, name: &str) -> Option<&Id> {
+        self.peers.keys().find(|id| id.name() == name)
+    }
+
+    pub fn presence(&self, id: &Id) -> Option<&Presence> {
+        self.peers.get(id)
+    }
+
+    pub fn set_presence(&self, status: Status, text: Option<String>) -> TResult {
+        self.send_packet(ClientPacket::SetPresence(Presence {
+            status, text
+        }))
+    }
+
+    // Call on every keystroke, notices go out unreliably and at most every `TYPING_INTERVAL`
+    pub fn typing(&mut self, target_mode: TargetMode) -> TResult {
+        let interval = Duration::from_secs_f32(TYPING_INTERVAL);
+        if self.typing_sent.get(&target_mode).is_some_and(|sent| sent.elapsed() < interval) {
+            return Ok(())
+        }
+        let addr = self.remote_addr.ok_or(TellErr::Lib(LibErr::NotConnected))?;
+        self.typing_sent.insert(target_mode.clone(), Instant::now());
+        self.adapter.send_command(SendMode::Unicast(addr), CHANNEL_UNRELIABLE,
+            PacketType::Client(ClientPacket::Typing(target_mode)))
+    }
+
+    // Peers that are writing right now, and where to
+    pub fn typing_peers(&self) -> Vec<(&Id, &TargetMode)> {
+        let timeout = Duration::from_secs_f32(TYPING_TIMEOUT);
+        self.typing.iter()
+            .filter(|(_, since)| since.elapsed() < timeout)
+            .map(|((id, target_mode), _)| (id, target_mode))
+            .collect()
+    }
+
+    pub fn join(&self, room: String) -> TResult {
+        self.send_packet(ClientPacket::Join(room))
+    }
+
+    pub fn leave(&self, room: String) -> TResult {
+        self.send_packet(ClientPacket::Leave(room))
+    }
+
+    pub fn list_rooms(&self) -> TResult {
+        self.send_packet(ClientPacket::ListRooms)
+    }
+
+    pub fn set_topic(&self, room: String, topic: String) -> TResult {
+        self.send_packet(ClientPacket::SetTopic(room, topic))
+    }
+
+    pub fn moderate(&self, room: String, action: ModAction) -> TResult {
+        self.send_packet(ClientPacket::Moderate(room, action))
+    }
+
+    // Own messages only. Whispers can't be edited.
+    pub fn edit(&self, id: u64, text: String) -> TResult {
+        self.send_packet(ClientPacket::Edit(id, text))
+    }
+
+    // Own messages, or as operator those of members below us
+    pub fn delete(&self, id: u64) -> TResult {
+        self.send_packet(ClientPacket::Delete(id))
+    }
+
+    pub fn react(&self, id: u64, emoji: String) -> TResult {
+        self.send_packet(ClientPacket::React(id, emoji))
+    }
+
+    pub fn unreact(&self, id: u64, emoji: String) -> TResult {
+        self.send_packet(ClientPacket::Unreact(id, emoji))
+    }
+
+    // Load the page before the oldest message of the scope. Returns false if there's none.
+    pub fn more_history(&self, scope: Scope) -> TResult<bool> {
+        match self.older.get(&scope) {
+            Some(&before) => {
+                self.send_packet(ClientPacket::RequestHistory {
+                    scope, before: Some(before)
+                })?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    pub fn rooms(&self) -> &HashMap<String, RoomView> {
+        &self.rooms
+    }
+
+    pub fn room(&self, room: &str) -> Option<&RoomView> {
+        self.rooms.get(room)
+    }
+
+    pub fn room_list(&self) -> &Vec<RoomInfo> {
+        &self.room_list
+    }
+
+    pub fn whisper_log(&self) -> &Vec<Whisper> {
+        &self.whisper_log
+    }
+
+    pub fn sent(&self) -> &Vec<Outgoing> {
+        &self.sent
+    }
+
+    pub fn unread(&self) -> &Vec<u64> {
+        &self.unread
+    }
+
+    // Tells the sender with the next batch
+    pub fn read(&mut self, id: u64) {
+        if let Some(i) = self.unread.iter().position(|&unread| unread == id) {
+            self.unread.remove(i);
+            self.read.push(id);
+            if let Some(whisper) = self.whisper_log.iter_mut().find(|whisper| whisper.id == Some(id)) {
+                whisper.state = Receipt::Read;
+            }
+        }
+    }
+
+    pub fn read_all(&mut self) {
+        for id in self.unread.clone().into_iter() {
+            self.read(id);
+        }
+    }
+
+    pub fn chat_log(&self) -> &Vec<ChatEntry> {
+        &self.chat_log
+    }
+
+    pub fn thread(&self, root: u64) -> Option<&Vec<ChatEntry>> {
+        self.threads.get(&root)
+    }
+
+    fn chat_log_mut(&mut self, scope: &Scope) -> Option<&mut Vec<ChatEntry>> {
+        match scope {
+            Scope::Global => Some(&mut self.chat_log),
+            Scope::Room(room) => self.rooms.get_mut(room).map(|view| &mut view.chat_log)
+        }
+    }
+
+    // Replies live in their thread
+    fn entry_mut(&mut self, scope: &Scope, id: u64) -> Option<&mut ChatEntry> {
+        let log = match scope {
+            Scope::Global => Some(&mut self.chat_log),
+            Scope::Room(room) => self.rooms.get_mut(room).map(|view| &mut view.chat_log)
+        };
+        log.into_iter().chain(self.threads.values_mut())
+            .flat_map(|log| log.iter_mut())
+            .find(|entry| entry.id == id)
+    }
+
+    // Replies may come in live and with the fetched thread, keep each once and in order
+    fn merge_thread(&mut self, root: u64, entries: Vec<ChatEntry>) {
+        let thread = self.threads.entry(root).or_default();
+        for entry in entries {
+            match thread.binary_search_by_key(&entry.id, |entry| entry.id) {
+                Ok(i) => thread[i] = entry,
+                Err(i) => thread.insert(i, entry)
+            }
+        }
     }
 
     pub fn print_metrics(&self) {
         self.adapter.shared_state.lock().unwrap().conns.values().for_each(|conn| {
-            info!("{:?}{:?} metrics: Sent {:?}, Recv {:?}",
-                conn.addr(), conn.id(), conn.send_metrics(), conn.recv_metrics());
+            info!("{:?}{:?} metrics: Sent {:?}, Recv {:?}, Link {:?}",
+                conn.addr(), conn.id(), conn.send_metrics(), conn.recv_metrics(), conn.link_metrics());
         })
     }
 
@@ -105,7 +426,72 @@ impl Client {
                 info!("Client event: {:?}.", ev);
                 self.handle_event(ev)?;
             }
-        })
+        }).and_then(|_| self.flush_receipts()).and_then(|_| self.send_chunks())
+    }
+
+    // As many chunks as the window of each transfer allows
+    fn send_chunks(&mut self) -> TResult {
+        if !self.transfers.iter().any(|transfer| !transfer.incoming && transfer.state == TransferState::Active) {
+            return Ok(())
+        }
+        let addr = match self.remote_addr {
+            Some(addr) if self.connected().is_some() => addr,
+            _ => return Ok(())
+        };
+        let chunks = self.transfers.iter_mut()
+            .filter(|transfer| !transfer.incoming)
+            .flat_map(|transfer| std::iter::from_fn(|| transfer.next_chunk()))
+            .collect::<Vec<_>>();
+        for chunk in chunks.into_iter() {
+            self.adapter.send_command(SendMode::Unicast(addr), CHANNEL_BULK,
+                PacketType::Client(ClientPacket::Chunk(chunk)))?;
+        }
+        Ok(())
+    }
+
+    // Acks what arrived of the transfer, and checks the file once it's all there
+    fn settle_incoming(&mut self, id: u64) -> TResult {
+        let transfer = match self.transfers.iter_mut().find(|transfer| transfer.incoming && transfer.id == Some(id)) {
+            Some(transfer) => transfer,
+            None => return Ok(())
+        };
+        let next = transfer.next;
+        if !transfer.done() {
+            if next > 0 && next % FILE_ACK_EVERY == 0 {
+                self.transfer_events.push(TransferEvent::Progress(id, transfer.progress()));
+                self.send_packet(ClientPacket::ChunkAck(id, next))?;
+            }
+            return Ok(())
+        }
+        if crate::transfer::hash(&transfer.data) == transfer.file.hash {
+            info!("[Transfer] #{id} Got {:?} ({}b).", transfer.file.name, transfer.file.size);
+            transfer.state = TransferState::Completed;
+            self.transfer_events.push(TransferEvent::Progress(id, transfer.progress()));
+            self.transfer_events.push(TransferEvent::Completed(id));
+            self.send_packet(ClientPacket::ChunkAck(id, next))
+        } else {
+            warn!("[Transfer] #{id} {:?} is corrupted.", transfer.file.name);
+            transfer.state = TransferState::Failed;
+            self.transfer_events.push(TransferEvent::Corrupted(id));
+            self.send_packet(ClientPacket::CancelTransfer(id))
+        }
+    }
+
+    // Receipts of what we got and read go out in batches
+    fn flush_receipts(&mut self) -> TResult {
+        if (self.delivered.is_empty() && self.read.is_empty())
+            || self.receipts_flushed.elapsed().as_secs_f32() < RECEIPT_INTERVAL || self.connected().is_none() {
+            return Ok(())
+        }
+        self.receipts_flushed = Instant::now();
+        while !self.delivered.is_empty() || !self.read.is_empty() {
+            let delivered = self.delivered.drain(..self.delivered.len().min(MAX_RECEIPT_BATCH)).collect();
+            let read = self.read.drain(..self.read.len().min(MAX_RECEIPT_BATCH)).collect();
+            self.send_packet(ClientPacket::Receipts {
+                delivered, read
+            })?;
+        }
+        Ok(())
     }
 
     fn handle_event(&mut self, ev: UdpAdapterEvent) -> TResult {
@@ -118,14 +504,18 @@ impl Client {
                 warn!("[Disconnect] Server {:?}{addr} disconnected. Reason: {:?}.", id, reason);
                 if let Some(conn) = self.adapter.shared_state.lock()
                     .unwrap().remove_conn(addr) {
-                    info!("Server ({:?}) metrics: {:?}, {:?}.",
-                        conn.conn_state(), conn.send_metrics(), conn.recv_metrics());
+                    info!("Server ({:?}) metrics: {:?}, {:?}, {:?}.",
+                        conn.conn_state(), conn.send_metrics(), conn.recv_metrics(), conn.link_metrics());
                 } else {
                     info!("Received invalid disconnect packet due to missing connection handle: {:?}{addr}, reason = {:?}", id, reason)
                 }
                 Ok(())
             },
-            UdpAdapterEvent::Payload(addr, packet) => {
+            UdpAdapterEvent::ProtocolViolation(addr, id, violation, strikes) => {
+                warn!("[Violation] Server {:?}{addr} violated protocol: {:?} ({strikes} strikes).", id, violation);
+                Ok(())
+            },
+            UdpAdapterEvent::Payload(addr, _channel, packet) => {
                 let Packet {
                     header, payload
                 } = packet;
@@ -143,38 +533,303 @@ impl Client {
         }
     }
 
+    fn handle_transfer(&mut self, packet: ServerPacket) -> TResult {
+        match packet {
+            ServerPacket::Offer { id, source, target, file } => {
+                if source == self.id {
+                    // Our own offer, now with an id
+                    if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.id.is_none()
+                        && transfer.state == TransferState::Offered && transfer.peer == target && transfer.file == file) {
+                        transfer.id = Some(id);
+                    }
+                } else {
+                    info!("[Transfer] #{id} {:?} offers {:?} ({}b).", source, file.name, file.size);
+                    self.transfers.push(Transfer::incoming(id, source, file));
+                    self.transfer_events.push(TransferEvent::Offered(id));
+                }
+                Ok(())
+            },
+            ServerPacket::Accepted(id, from) => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| !transfer.incoming && transfer.id == Some(id)) {
+                    if matches!(transfer.state, TransferState::Offered | TransferState::Active) {
+                        info!("[Transfer] #{id} Sending {:?} from chunk {from} on.", transfer.file.name);
+                        transfer.state = TransferState::Active;
+                        transfer.next = from;
+                        transfer.acked = from;
+                    }
+                }
+                Ok(())
+            },
+            ServerPacket::Chunk(chunk) => {
+                let id = chunk.transfer;
+                let transfer = match self.transfers.iter_mut().find(|transfer| transfer.incoming && transfer.id == Some(id)) {
+                    Some(transfer) if transfer.state == TransferState::Active => transfer,
+                    _ => return Ok(())
+                };
+                match transfer.receive(chunk) {
+                    Received::Taken => self.settle_incoming(id),
+                    Received::Skipped => Ok(()),
+                    Received::Broken => {
+                        warn!("[Transfer] #{id} Chunk {} is broken, asking again.", transfer.next);
+                        let next = transfer.next;
+                        self.send_packet(ClientPacket::Accept(id, next))
+                    }
+                }
+            },
+            ServerPacket::ChunkAck(id, next) => {
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| !transfer.incoming && transfer.id == Some(id)) {
+                    transfer.acked = next.clamp(transfer.acked, transfer.file.chunks());
+                    self.transfer_events.push(TransferEvent::Progress(id, transfer.progress()));
+                    if transfer.acked == transfer.file.chunks() && transfer.state == TransferState::Active {
+                        info!("[Transfer] #{id} Sent {:?}.", transfer.file.name);
+                        transfer.state = TransferState::Completed;
+                        self.transfer_events.push(TransferEvent::Completed(id));
+                    }
+                }
+                Ok(())
+            },
+            ServerPacket::TransferCancelled(id, by) => {
+                info!("[Transfer] #{id} Cancelled by {:?}.", by);
+                if let Some(transfer) = self.transfers.iter_mut().find(|transfer| transfer.id == Some(id)) {
+                    if matches!(transfer.state, TransferState::Offered | TransferState::Active) {
+                        transfer.state = TransferState::Cancelled;
+                        self.transfer_events.push(TransferEvent::Cancelled(id));
+                    }
+                }
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+
     fn handle_payload(&mut self, addr: SocketAddr, id: Id, packet: ServerPacket) -> TResult {
         match packet {
-            // ServerPacket::PeerConnected(id) => {
-            //     info!("New peer connected: {:?}.", id);
-
-            //     Ok(())
-            // },
-            // ServerPacket::PeerDisconnected(id, reason) => {
-            //     Ok(())
-            // },
-            ServerPacket::Message { source, target_mode, text } => {
+            // Other peers coming and going
+            p @ (ServerPacket::ConnectAccepted(_) | ServerPacket::PeerConnected(_) | ServerPacket::PeerDisconnected(..)) =>
+                self.handle_connect_event(addr, id, p),
+            ServerPacket::Message { id: message, source, target_mode, text, parent: Some(root) } => {
+                // Done writing
+                self.typing.remove(&(source.clone(), target_mode.clone()));
+                info!("[Thread #{root}] #{message} {:?}: {text}.", source);
+                let scope = match target_mode {
+                    TargetMode::Room(room) => Scope::Room(room),
+                    _ => Scope::Global
+                };
+                if let Some(entry) = self.entry_mut(&scope, root) {
+                    entry.replies += 1;
+                }
+                self.merge_thread(root, vec![ChatEntry {
+                    id: message, source, text, state: MessageState::Sent, reactions: vec![], parent: Some(root), replies: 0
+                }]);
+                Ok(())
+            },
+            ServerPacket::Message { id: message, source, target_mode, text, parent: None } => {
+                self.typing.remove(&(source.clone(), target_mode.clone()));
                 let target = match target_mode {
                     TargetMode::Broadcast => "broadcasted".to_owned(),
-                    TargetMode::Multicast(ids) => format!("wrote to {:?}", ids),
+                    TargetMode::Multicast(ids) => {
+                        self.delivered.push(message);
+                        self.unread.push(message);
+                        format!("wrote to {:?}", ids)
+                    },
                     TargetMode::Unicast(id) => {
                         if self.id  != id {
                             info!("Oops. Personal message to {:?} was eavesdropped by you.", id);
                         }
-                        "whispered to you".to_owned()
+                        info!("[Whisper] {:?} whispered to you: {text}.", source);
+                        self.whisper_log.push(Whisper {
+                            id: Some(message), source, target: id, text, state: Receipt::Delivered
+                        });
+                        self.delivered.push(message);
+                        self.unread.push(message);
+                        return Ok(())
+                    },
+                    TargetMode::Room(room) => {
+                        match self.rooms.get_mut(&room) {
+                            Some(view) => {
+                                info!("[{room}] #{message} {:?}: {text}.", source);
+                                view.chat_log.push(ChatEntry {
+                                    id: message, source, text, state: MessageState::Sent, reactions: vec![], parent: None, replies: 0
+                                });
+                            },
+                            None => warn!("Recv message to {room}, which we're not in.")
+                        }
+                        return Ok(())
+                    }
+                };
+                info!("[Message] #{message} {:?} {}: {text}.", source, target);
+                self.chat_log.push(ChatEntry {
+                    id: message, source, text, state: MessageState::Sent, reactions: vec![], parent: None, replies: 0
+                });
+                Ok(())
+            },
+            ServerPacket::Edited { scope, id: message, text } => {
+                info!("[{:?}] #{message} was edited: {text}.", scope);
+                if let Some(entry) = self.entry_mut(&scope, message) {
+                    entry.text = text;
+                    entry.state = MessageState::Edited;
+                }
+                Ok(())
+            },
+            ServerPacket::Deleted { scope, id: message, source } => {
+                info!("[{:?}] #{message} was deleted by {:?}.", scope, source);
+                if let Some(entry) = self.entry_mut(&scope, message) {
+                    entry.text.clear();
+                    entry.state = MessageState::Deleted;
+                    entry.reactions.clear();
+                }
+                Ok(())
+            },
+            ServerPacket::Reactions { scope, id: message, reactions } => {
+                info!("[{:?}] Reactions on #{message}: {}.", scope, reactions.iter()
+                    .map(|reaction| format!("{} {}", reaction.emoji, reaction.count()))
+                    .collect::<Vec<_>>().join(", "));
+                if let Some(entry) = self.entry_mut(&scope, message) {
+                    entry.reactions = reactions;
+                }
+                Ok(())
+            },
+            ServerPacket::Sent { id: message, receipts } => {
+                info!("Sent #{message}: {:?}.", receipts);
+                // Answers come in the order we sent the messages
+                if let Some(outgoing) = self.sent.iter_mut().find(|outgoing| outgoing.id.is_none()) {
+                    outgoing.id = Some(message);
+                    if let TargetMode::Unicast(_) = outgoing.target_mode {
+                        if let Some(whisper) = self.whisper_log.iter_mut()
+                            .find(|whisper| whisper.source == self.id && whisper.id.is_none()) {
+                            whisper.id = Some(message);
+                        }
+                    }
+                }
+                for (recipient, receipt) in receipts.into_iter() {
+                    self.settle(message, recipient, receipt);
+                }
+                Ok(())
+            },
+            ServerPacket::Receipts(updates) => {
+                info!("Receipts: {:?}.", updates);
+                for (message, recipient, receipt) in updates.into_iter() {
+                    self.settle(message, recipient, receipt);
+                }
+                Ok(())
+            },
+            ServerPacket::Error(e) => {
+                warn!("Server couldn't carry out request: {:?}.", e);
+                // Offers are answered in order
+                let offer = match &e {
+                    RequestError::FileTooLarge(_) | RequestError::InvalidFile | RequestError::TooManyOffers(_) => self.transfers.iter_mut()
+                        .find(|transfer| transfer.id.is_none() && transfer.state == TransferState::Offered),
+                    RequestError::PeerOffline(target) => self.transfers.iter_mut()
+                        .find(|transfer| transfer.id.is_none() && transfer.state == TransferState::Offered && &transfer.peer == target),
+                    _ => None
+                };
+                if let Some(offer) = offer {
+                    offer.state = TransferState::Failed;
+                    self.transfer_events.push(TransferEvent::Rejected(offer.file.name.clone(), e));
+                }
+                Ok(())
+            },
+            p @ (ServerPacket::Offer { .. } | ServerPacket::Accepted(..) | ServerPacket::Chunk(_)
+                | ServerPacket::ChunkAck(..) | ServerPacket::TransferCancelled(..)) => self.handle_transfer(p),
+            ServerPacket::Joined { room, topic, topic_locked, members } => {
+                info!("[{room}] Joined. Topic: {:?}, members: {:?}.", topic, members);
+                let (ours, peers): (Vec<_>, Vec<_>) = members.into_iter().partition(|(id, _)| id == &self.id);
+                self.rooms.insert(room, RoomView {
+                    topic, topic_locked, role: ours.first().map_or(Role::Member, |&(_, role)| role),
+                    peers: peers.into_iter().collect(), chat_log: vec![]
+                });
+                Ok(())
+            },
+            ServerPacket::PeerJoined(room, id) => {
+                info!("[{room}] {:?} joined.", id);
+                if let Some(view) = self.rooms.get_mut(&room) {
+                    view.peers.insert(id, Role::Member);
+                }
+                Ok(())
+            },
+            ServerPacket::PeerLeft(room, id, reason) => {
+                if id == self.id {
+                    match reason {
+                        DisconnectReason::Kicked => warn!("[{room}] You were removed from the room."),
+                        _ => info!("[{room}] Left.")
                     }
+                    self.older.remove(&Scope::Room(room.clone()));
+                    self.rooms.remove(&room);
+                } else if let Some(view) = self.rooms.get_mut(&room) {
+                    info!("[{room}] {:?} left. Reason: {:?}.", id, reason);
+                    view.peers.remove(&id);
+                }
+                Ok(())
+            },
+            ServerPacket::Moderation { room, source, action } => {
+                info!("[{room}] {:?} moderated: {:?}.", source, action);
+                if let Some(view) = self.rooms.get_mut(&room) {
+                    match action {
+                        ModAction::SetRole(target, role) if target == self.id => view.role = role,
+                        ModAction::SetRole(target, role) => {
+                            view.peers.insert(target, role);
+                        },
+                        ModAction::LockTopic(locked) => view.topic_locked = locked,
+                        // Removals follow as PeerLeft
+                        _ => ()
+                    }
+                }
+                Ok(())
+            },
+            ServerPacket::History { scope, records, more } => {
+                info!("Recv {} records of {:?} history.", records.len(), scope);
+                match records.first() {
+                    Some(oldest) if more => self.older.insert(scope.clone(), oldest.seq),
+                    _ => self.older.remove(&scope)
                 };
-                info!("[Message] {:?} {}: {text}.", source, target);
-                self.chat_log.push((source, text));
+                if let Some(log) = self.chat_log_mut(&scope) {
+                    log.splice(0..0, records.into_iter().map(ChatEntry::from));
+                }
+                Ok(())
+            },
+            ServerPacket::Thread { scope, root, replies } => {
+                info!("[{:?}] Recv {} replies of thread #{root}.", scope, replies.len());
+                self.merge_thread(root, replies.into_iter().map(ChatEntry::from).collect());
+                Ok(())
+            },
+            ServerPacket::RoomList(list) => {
+                info!("Server sent room list:");
+                for info in list.iter() {
+                    println!("{} ({} members): {}", info.name, info.members, info.topic.as_deref().unwrap_or(""));
+                }
+                self.room_list = list;
+                Ok(())
+            },
+            ServerPacket::Topic { room, source, topic } => {
+                info!("[{room}] {:?} set the topic: {:?}.", source, topic);
+                if let Some(view) = self.rooms.get_mut(&room) {
+                    view.topic = topic;
+                }
                 Ok(())
             },
-            ServerPacket::RequestReply(ids) => {
+            ServerPacket::RequestReply(peers) => {
                 info!("Server sent peer list:");
-                for id in ids.into_iter() {
-                    println!("{:?}", id);
+                for (id, presence) in peers.into_iter() {
+                    println!("{:?} ({:?}{})", id, presence.status, presence.text.as_deref().map_or(String::new(), |text| format!(": {text}")));
+                    if id != self.id {
+                        self.peers.insert(id, presence);
+                    }
+                }
+                Ok(())
+            },
+            ServerPacket::Presence(id, presence) => {
+                info!("{:?} is {:?}. {}", id, presence.status, presence.text.as_deref().unwrap_or(""));
+                if let Some(known) = self.peers.get_mut(&id) {
+                    *known = presence;
                 }
                 Ok(())
             },
+            ServerPacket::Typing(id, target_mode) => {
+                let timeout = Duration::from_secs_f32(TYPING_TIMEOUT);
+                self.typing.retain(|_, since| since.elapsed() < timeout);
+                self.typing.insert((id, target_mode), Instant::now());
+                Ok(())
+            },
             p @ _ => Err(TellErr::Lib(LibErr::InvalidPacketType(
                 format!("Expected server request or message packet: Recv: {:?}.", p))))
 
@@ -183,16 +838,61 @@ impl Client {
 
     fn handle_connect_event(&mut self, addr: SocketAddr, source_id: Id, packet: ServerPacket) -> TResult {
         match packet {
-            ServerPacket::PeerConnected(id) => {
-                if self.id == id {
-                    info!("Server accepted connection!");
-                    self.adapter.shared_state.lock().unwrap().conns
-                        .get_mut(&addr).unwrap().connect(source_id)
-                } else {
-                    info!("Peer {:?} connected.", id);
-                    self.peers.insert(id);
-                    Ok(())
+            ServerPacket::ConnectAccepted(handshake) => {
+                info!("Server accepted connection! Session: {}, protocol version: {}, capabilities: {:?}, compression: {:?}.",
+                    handshake.session, handshake.version, handshake.capabilities, handshake.compression);
+                let mut _shared_state = self.adapter.shared_state.lock().unwrap();
+                let conn = _shared_state.conns.get_mut(&addr).unwrap();
+                // A sealed accept proves the server holds the expected key
+                if self.server_key.is_some() && !(conn.encrypted()
+                    && handshake.capabilities.contains(Capabilities::ENCRYPTION)) {
+                    error!("Server {:?}{addr} didn't agree to an encrypted session.", source_id);
+                    drop(_shared_state);
+                    self.reset_connection()?;
+                    return Err(TellErr::Lib(LibErr::HandshakeFailed))
+                }
+                conn.set_capabilities(handshake.capabilities);
+                conn.set_compression(handshake.compression);
+                conn.set_session(handshake.session, true);
+                _shared_state.establish(addr, source_id)?;
+                drop(_shared_state);
+                if handshake.mailbox.is_some() {
+                    self.mailbox = handshake.mailbox;
                 }
+                // Peers that were there before us
+                self.request_peers()?;
+                // Files we were getting before we went away
+                let resumed = self.transfers.iter()
+                    .filter(|transfer| transfer.incoming && transfer.state == TransferState::Active)
+                    .filter_map(|transfer| transfer.id.map(|id| (id, transfer.next)))
+                    .collect::<Vec<_>>();
+                for (transfer, next) in resumed.into_iter() {
+                    self.send_packet(ClientPacket::Accept(transfer, next))?;
+                }
+                Ok(())
+            },
+            ServerPacket::PeerConnected(id) => {
+                info!("Peer {:?} connected.", id);
+                self.peers.insert(id, Presence::default());
+                Ok(())
+            },
+            // Answered to every retransmission of the first connect, until the server accepted
+            // one carrying the cookie. Goes out unsequenced, the server's reliable channel then
+            // starts with the first connect and everything queued behind it still arrives.
+            ServerPacket::Retry(cookie) => match self.hello.as_mut() {
+                Some(hello) => {
+                    info!("Server {:?}{addr} asked to retry with cookie.", source_id);
+                    hello.cookie = Some(cookie);
+                    let hello = hello.encode()?;
+                    self.adapter.send_command(SendMode::Unicast(addr), CHANNEL_UNRELIABLE,
+                    PacketType::Client(ClientPacket::Connect(PROTOCOL_VERSION, hello)))
+                },
+                None => Ok(())
+            },
+            ServerPacket::ConnectRejected(reason) => {
+                error!("Server {:?}{addr} rejected connection: {:?}.", source_id, reason);
+                self.reset_connection()?;
+                Err(TellErr::Lib(LibErr::ConnectionRejected(reason)))
             },
             ServerPacket::PeerDisconnected(id, reason) => {
                 if self.id == source_id {
@@ -201,17 +901,40 @@ impl Client {
                 } else {
                     info!("Peer {:?} disconnected. Reason: {:?}.", id, reason);
                     self.peers.remove(&id);
+                    self.typing.retain(|(typist, _), _| typist != &id);
+                    for view in self.rooms.values_mut() {
+                        view.peers.remove(&id);
+                    }
                     Ok(())
                 }
             },
-            p @ _ => Err(TellErr::Lib(LibErr::InvalidPacketType(format!("Expected peer connected packet from server. Recv: {:?}.", p))))
+            // Still relayed to our last session, it's resumed once we're connected
+            ServerPacket::Chunk(chunk) => {
+                warn!("Dropped chunk {} of transfer #{} before the server accepted us.", chunk.index, chunk.transfer);
+                Ok(())
+            },
+            p @ _ => Err(TellErr::Lib(LibErr::InvalidPacketType(format!("Expected connect accepted packet from server. Recv: {:?}.", p))))
+
+        }
+    }
 
+    // Receipts only move forward, late ones of a batch change nothing
+    fn settle(&mut self, message: u64, recipient: Id, receipt: Receipt) {
+        let outgoing = match self.sent.iter_mut().find(|outgoing| outgoing.id == Some(message)) {
+            Some(outgoing) => outgoing,
+            None => return
+        };
+        let current = outgoing.receipts.entry(recipient).or_insert(receipt);
+        *current = receipt.max(*current);
+        let state = outgoing.state();
+        if let Some(whisper) = self.whisper_log.iter_mut().find(|whisper| whisper.source == self.id && whisper.id == Some(message)) {
+            whisper.state = state;
         }
     }
 
     fn send_packet(&self, packet: ClientPacket) -> TResult {
         if let Some(addr) = self.remote_addr.as_ref() {
-            self.adapter.send_command(SendMode::Unicast(*addr), 
+            self.adapter.send_command(SendMode::Unicast(*addr), CHANNEL_RELIABLE,
             PacketType::Client(packet))
         } else {
             Err(TellErr::Lib(LibErr::NotConnected))
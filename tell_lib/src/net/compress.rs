@@ -0,0 +1,96 @@
+use std::io::{Read, Write};
+use flate2::{Compression as Level, write::DeflateEncoder, read::DeflateDecoder};
+use serde::{Serialize, Deserialize};
+use crate::err::{TResult, TellErr, LibErr};
+use super::adapter::UDP_READ_BUF_SIZE;
+
+// Datagrams below this size aren't worth compressing
+pub const UDP_COMPRESSION_THRESHOLD: usize = 128;
+// Guards against decompression bombs
+pub const UDP_MAX_DECOMPRESSED_SIZE: usize = UDP_READ_BUF_SIZE * 16;
+
+// Every datagram starts with a byte telling how the rest is compressed. This way
+// the receiver can always decode, even before the handshake told it what to expect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Deflate
+}
+
+impl Compression {
+    // Pick the first offered algorithm that we support as well
+    pub fn negotiate(offered: &[Compression], supported: &[Compression]) -> Compression {
+        offered.iter().copied()
+            .find(|c| supported.contains(c))
+            .unwrap_or(Compression::None)
+    }
+
+    fn flag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1
+        }
+    }
+
+    // Prefix and (if worth it) compress a serialized packet
+    pub fn encode(self, bytes: Vec<u8>) -> TResult<Vec<u8>> {
+        if self != Compression::None && bytes.len() >= UDP_COMPRESSION_THRESHOLD {
+            let mut encoder = DeflateEncoder::new(vec![self.flag()], Level::fast());
+            encoder.write_all(&bytes)?;
+            let compressed = encoder.finish()?;
+            if compressed.len() < bytes.len() + 1 {
+                return Ok(compressed)
+            }
+        }
+        let mut framed = Vec::with_capacity(bytes.len() + 1);
+        framed.push(Compression::None.flag());
+        framed.extend(bytes);
+        Ok(framed)
+    }
+
+    pub fn decode(bytes: &[u8]) -> TResult<Vec<u8>> {
+        match bytes.split_first() {
+            Some((0, bytes)) => Ok(bytes.to_vec()),
+            Some((1, bytes)) => {
+                let mut decompressed = vec![];
+                DeflateDecoder::new(bytes)
+                    .take(UDP_MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > UDP_MAX_DECOMPRESSED_SIZE {
+                    Err(TellErr::Lib(LibErr::PacketTooLarge(decompressed.len())))
+                } else {
+                    Ok(decompressed)
+                }
+            },
+            Some((&flag, _)) => Err(TellErr::Lib(LibErr::InvalidCompression(flag))),
+            None => Err(TellErr::Lib(LibErr::InvalidCompression(0)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, UDP_COMPRESSION_THRESHOLD};
+
+    #[test]
+    fn roundtrip() {
+        let text = "Hello world! ".repeat(20).into_bytes();
+        let framed = Compression::Deflate.encode(text.clone()).unwrap();
+        assert!(framed.len() < text.len() / 2);
+        assert_eq!(Compression::decode(&framed).unwrap(), text);
+
+        // Too small to bother
+        let small = vec![7u8; UDP_COMPRESSION_THRESHOLD - 1];
+        let framed = Compression::Deflate.encode(small.clone()).unwrap();
+        assert_eq!(framed.len(), small.len() + 1);
+        assert_eq!(Compression::decode(&framed).unwrap(), small);
+        assert!(Compression::decode(&[9, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn negotiate() {
+        use Compression::*;
+        assert_eq!(Compression::negotiate(&[Deflate], &[Deflate]), Deflate);
+        assert_eq!(Compression::negotiate(&[Deflate], &[]), None);
+    }
+}
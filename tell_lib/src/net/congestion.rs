@@ -0,0 +1,102 @@
+use std::time::{Instant, Duration};
+use super::reliable::RELIABLE_WINDOW_SIZE;
+
+// Congestion window bounds, in reliable packets in flight
+pub const UDP_INITIAL_WINDOW: f32 = 4.;
+pub const UDP_MIN_WINDOW: f32 = 2.;
+// Never exceed what the receiver is willing to buffer
+pub const UDP_MAX_WINDOW: f32 = RELIABLE_WINDOW_SIZE as f32;
+
+// AIMD congestion control: slow start until the first loss, then additive increase
+// by one packet per window and multiplicative decrease (at most once per rtt) on loss.
+// Sends are paced evenly over the rtt instead of bursting the whole window.
+pub struct Congestion {
+    window: f32,
+    threshold: f32,
+    next_send: Instant,
+    // Losses until then belong to the same congestion event
+    recovery_until: Instant
+}
+
+impl Default for Congestion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Congestion {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            window: UDP_INITIAL_WINDOW, threshold: UDP_MAX_WINDOW,
+            next_send: now, recovery_until: now
+        }
+    }
+
+    pub fn window(&self) -> f32 {
+        self.window
+    }
+
+    pub fn on_ack(&mut self, acked: usize) {
+        for _ in 0..acked {
+            self.window += if self.window < self.threshold {
+                1.
+            } else {
+                1. / self.window
+            };
+        }
+        self.window = self.window.min(UDP_MAX_WINDOW);
+    }
+
+    pub fn on_loss(&mut self, rtt: f32) {
+        let now = Instant::now();
+        if now < self.recovery_until {
+            return
+        }
+        self.threshold = (self.window / 2.).max(UDP_MIN_WINDOW);
+        self.window = self.threshold;
+        self.recovery_until = now + Duration::from_secs_f32(rtt);
+    }
+
+    // Any packet is paced, only reliable ones occupy the window
+    pub fn paced(&self) -> bool {
+        Instant::now() >= self.next_send
+    }
+
+    pub fn window_open(&self, in_flight: usize) -> bool {
+        (in_flight as f32) < self.window
+    }
+
+    pub fn sent(&mut self, rtt: f32) {
+        self.next_send = Instant::now() + Duration::from_secs_f32(rtt / self.window);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Congestion, UDP_INITIAL_WINDOW, UDP_MIN_WINDOW};
+
+    #[test]
+    fn aimd() {
+        let mut congestion = Congestion::new();
+        assert!(congestion.paced());
+        assert!(congestion.window_open(UDP_INITIAL_WINDOW as usize - 1));
+        assert!(!congestion.window_open(UDP_INITIAL_WINDOW as usize));
+        // Slow start
+        congestion.on_ack(4);
+        assert_eq!(congestion.window(), 8.);
+        congestion.on_loss(1.);
+        assert_eq!(congestion.window(), 4.);
+        // Same congestion event
+        congestion.on_loss(1.);
+        assert_eq!(congestion.window(), 4.);
+        // Congestion avoidance
+        congestion.on_ack(4);
+        assert!(congestion.window() > 4. && congestion.window() < 5.);
+        congestion.recovery_until = std::time::Instant::now();
+        for _ in 0..10 {
+            congestion.on_loss(0.);
+        }
+        assert_eq!(congestion.window(), UDP_MIN_WINDOW);
+    }
+}
@@ -1,12 +1,24 @@
-use std::net::SocketAddr;
-use crate::{id::Id, util::Metrics, err::TResult};
+use std::{net::SocketAddr, collections::{BTreeMap, VecDeque}, time::Instant};
+use crate::{id::Id, util::{Metrics, LinkMetrics}, err::TResult, packet::{Packet, PacketType}, header::PacketHeader};
+use super::{reliable::{Channel, RecvWindow}, adapter::{Delivery, ChannelId, UDP_RETRANSMIT_TIMEOUT, UDP_ACK_DELAY}, fragment::{Reassembler, Fragment}, congestion::Congestion, compress::Compression, capability::Capabilities, secure::SecureState};
 
 pub trait Connection {
     fn addr(&self) -> SocketAddr;
     fn conn_state(&self) -> ConnectionState;
     fn id(&self) -> Option<&Id>;
     fn send_metrics(&self) -> Metrics;
+    // Bare heartbeats are counted, but only payloads and acks are activity
     fn recv_metrics(&self) -> Metrics;
+    // Last datagram of any kind, the connection times out without
+    fn last_heard(&self) -> Instant;
+    fn link_metrics(&self) -> LinkMetrics;
+    fn strikes(&self) -> u32;
+    // Features agreed on in the handshake
+    fn capabilities(&self) -> Capabilities;
+
+    fn has(&self, capability: Capabilities) -> bool {
+        self.capabilities().contains(capability)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -23,13 +35,36 @@ pub struct UdpConnection {
     conn_state: ConnectionState,
     id: Option<Id>,
     send_m: Metrics,
-    recv_m: Metrics
+    recv_m: Metrics,
+    heard: Instant,
+    link_m: LinkMetrics,
+    // Reliable channels, opened as soon as they're used by either side
+    channels: BTreeMap<ChannelId, Channel>,
+    fragments: Reassembler,
+    congestion: Congestion,
+    // What we may use towards the remote, as agreed on in the handshake
+    compression: Compression,
+    capabilities: Capabilities,
+    secure: SecureState,
+    // Outgoing packets held back by the congestion window/pacing
+    queue: VecDeque<(ChannelId, Delivery, PacketType)>,
+    // Protocol violations so far
+    strikes: u32,
+    // Disconnect was reported, waiting for the server/client to remove it
+    closed: bool,
+    session: Option<u32>,
+    // Remote knows the session, so headers can carry it instead of our id
+    session_confirmed: bool
 }
 
 impl UdpConnection {
     pub fn new(addr: SocketAddr, conn_state: ConnectionState, id: Option<Id>) -> Self {
         Self {
-            addr, conn_state, id, send_m: Metrics::new(), recv_m: Metrics::new()
+            addr, conn_state, id, send_m: Metrics::new(), recv_m: Metrics::new(), heard: Instant::now(),
+            link_m: LinkMetrics::new(), channels: BTreeMap::new(), fragments: Reassembler::new(),
+            congestion: Congestion::new(), compression: Compression::None,
+            capabilities: Capabilities::empty(), secure: SecureState::Plain, queue: VecDeque::new(), strikes: 0,
+            closed: false, session: None, session_confirmed: false
         }
     }
 
@@ -37,9 +72,14 @@ impl UdpConnection {
         Self::new(addr, ConnectionState::Connecting, None)
     }
 
-    pub fn incoming(addr: SocketAddr, id: Id) -> Self {
+    pub fn incoming(addr: SocketAddr, header: &PacketHeader) -> Self {
         // What about the approving state?
-        Self::new(addr, ConnectionState::Established, Some(id))
+        let mut conn = Self::new(addr, ConnectionState::Established, Some(header.source().clone()));
+        // The connect packet was already delivered through the adapter, continue after it
+        if let Some(seq) = header.seq() {
+            conn.channel(header.channel()).recv = RecvWindow::after(seq);
+        }
+        conn
     }
 
     pub fn connect(&mut self, id: Id) -> TResult {
@@ -53,13 +93,200 @@ impl UdpConnection {
         Ok(())
     }
 
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn set_session(&mut self, session: u32, confirmed: bool) {
+        self.session = Some(session);
+        self.session_confirmed = confirmed;
+    }
+
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    pub fn set_secure(&mut self, secure: SecureState) {
+        self.secure = secure;
+    }
+
+    // Both sides derived the session keys
+    pub fn encrypted(&self) -> bool {
+        self.secure.established()
+    }
+
+    pub fn seal(&mut self, bytes: Vec<u8>) -> TResult<Vec<u8>> {
+        self.secure.seal(bytes)
+    }
+
+    pub fn open(&mut self, frame: &[u8]) -> TResult<Vec<u8>> {
+        self.secure.open(frame)
+    }
+
+    // Frame a serialized packet for the wire
+    pub fn encode(&mut self, bytes: Vec<u8>) -> TResult<Vec<u8>> {
+        let raw_size = bytes.len() + 1;
+        let bytes = self.compression.encode(bytes)?;
+        self.send_m.compress(raw_size, bytes.len());
+        Ok(bytes)
+    }
+
     pub fn send(&mut self, size: usize) {
         self.send_m.transfer(size)
 
     }
 
-    pub fn recv(&mut self, size: usize) {
-        self.recv_m.transfer(size)
+    pub fn recv(&mut self, size: usize, raw_size: usize, keepalive: bool) {
+        self.heard = Instant::now();
+        if keepalive {
+            self.recv_m.count(size);
+        } else {
+            self.recv_m.transfer(size);
+        }
+        self.recv_m.compress(raw_size, size);
+    }
+
+    fn channel(&mut self, channel: ChannelId) -> &mut Channel {
+        self.channels.entry(channel).or_default()
+    }
+
+    // Assign sequence number and piggyback all pending acks onto an outgoing packet
+    pub fn stamp(&mut self, header: &mut PacketHeader, channel: ChannelId, delivery: Delivery) {
+        let seq = match delivery {
+            Delivery::Unreliable => None,
+            _ => Some(self.channel(channel).send.next_seq())
+        };
+        header.set_session(self.session.filter(|_| self.session_confirmed));
+        header.set_channel(channel, delivery, seq);
+        header.set_acks(self.channels.iter_mut()
+            .filter(|(_, ch)| ch.recv.needs_ack())
+            .map(|(&id, ch)| (id, ch.recv.take_ack()))
+            .collect());
+    }
+
+    pub fn track(&mut self, channel: ChannelId, seq: u32, bytes: Vec<u8>) {
+        self.channel(channel).send.track(seq, bytes)
+    }
+
+    // Process acks and return all packets that are ready to be delivered
+    pub fn accept(&mut self, packet: Packet) -> Vec<Packet> {
+        if packet.header().session().is_some() && packet.header().session() == self.session {
+            self.session_confirmed = true;
+        }
+        for &(channel, ack) in packet.header().acks() {
+            if let Some(ch) = self.channels.get_mut(&channel) {
+                let acked = ch.send.ack(ack);
+                for _ in 0..acked {
+                    self.link_m.loss_sample(false);
+                }
+                self.congestion.on_ack(acked);
+            }
+        }
+        let header = packet.header();
+        match (header.seq(), header.delivery()) {
+            (Some(seq), delivery) if delivery != Delivery::Unreliable => {
+                let ordered = delivery == Delivery::ReliableOrdered;
+                self.channel(header.channel()).recv.recv(seq, ordered, packet)
+            },
+            _ => vec![packet]
+        }
+    }
+
+    // Collect all reliable packets that weren't acked within the retransmission timeout
+    pub fn expired(&mut self) -> Vec<Vec<u8>> {
+        let timeout = self.rto();
+        let expired = self.channels.values_mut()
+            .flat_map(|ch| ch.send.expired(timeout))
+            .collect::<Vec<_>>();
+        for _ in 0..expired.len() {
+            self.link_m.loss_sample(true);
+        }
+        if !expired.is_empty() {
+            self.congestion.on_loss(self.link_m.rtt);
+        }
+        expired
+    }
+
+    pub fn enqueue(&mut self, channel: ChannelId, delivery: Delivery, packet: PacketType) {
+        self.queue.push_back((channel, delivery, packet));
+    }
+
+    // Next packet that may go out now. Unreliable packets can pass reliable ones
+    // while the window is full, order within a channel is kept either way.
+    pub fn dequeue(&mut self) -> Option<(ChannelId, Delivery, PacketType)> {
+        if !self.congestion.paced() {
+            return None
+        }
+        let window_open = self.congestion.window_open(self.in_flight());
+        let index = self.queue.iter().position(|&(_, delivery, _)| {
+            window_open || delivery == Delivery::Unreliable
+        })?;
+        self.congestion.sent(self.link_m.rtt);
+        self.queue.remove(index)
+    }
+
+    // Retransmission timeout, derived from the measured rtt once available
+    pub fn rto(&self) -> f32 {
+        if self.link_m.rtt_samples == 0 {
+            UDP_RETRANSMIT_TIMEOUT
+        } else {
+            (self.link_m.rtt + 4. * self.link_m.rtt_var + UDP_ACK_DELAY)
+                .clamp(UDP_ACK_DELAY * 2., UDP_RETRANSMIT_TIMEOUT * 10.)
+        }
+    }
+
+    // Process the echo of one of our heartbeats (both timestamps in microsecs of our clock)
+    pub fn heartbeat_reply(&mut self, sent: u64, now: u64) {
+        if sent <= now {
+            self.link_m.rtt_sample((now - sent) as f32 / 1e6);
+        }
+    }
+
+    // Header source must be the identity this connection was established with
+    pub fn verify_source(&self, header: &PacketHeader) -> bool {
+        self.id.as_ref().is_none_or(|id| id == header.source())
+    }
+
+    // True only the first time, so a connection's disconnect is reported once
+    pub fn close(&mut self) -> bool {
+        !std::mem::replace(&mut self.closed, true)
+    }
+
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    pub fn strike(&mut self) -> u32 {
+        self.strikes += 1;
+        self.strikes
+    }
+
+    pub fn reassemble(&mut self, fragment: Fragment) -> TResult<Option<Vec<u8>>> {
+        self.fragments.insert(fragment)
+    }
+
+    pub fn expire_fragments(&mut self) {
+        self.fragments.expire()
+    }
+
+    pub fn needs_ack(&self) -> bool {
+        self.channels.values().any(|ch| ch.recv.needs_ack())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.channels.values().map(|ch| ch.send.in_flight()).sum()
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.channels.values().map(|ch| ch.send.retries()).max().unwrap_or(0)
     }
 }
 
@@ -83,4 +310,24 @@ impl Connection for UdpConnection {
     fn recv_metrics(&self) -> Metrics {
         self.recv_m
     }
+
+    fn last_heard(&self) -> Instant {
+        self.heard
+    }
+
+    fn link_metrics(&self) -> LinkMetrics {
+        let mut link_m = self.link_m;
+        link_m.window = self.congestion.window();
+        link_m.in_flight = self.in_flight();
+        link_m.queued = self.queue.len();
+        link_m
+    }
+
+    fn strikes(&self) -> u32 {
+        self.strikes
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
 }
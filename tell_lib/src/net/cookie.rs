@@ -0,0 +1,81 @@
+use std::{net::{SocketAddr, IpAddr}, time::{SystemTime, UNIX_EPOCH}};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+
+// Cookies are valid for the current and the previous period (secs)
+pub const COOKIE_PERIOD: u64 = 10;
+pub const COOKIE_SIZE: usize = 16;
+
+pub type Cookie = [u8; COOKIE_SIZE];
+
+// Proves that a connecting client can receive at its source address, without the
+// server keeping any state until it does: the cookie is an HMAC of the address.
+pub struct CookieJar {
+    secret: [u8; 32]
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Self {
+            secret
+        }
+    }
+
+    fn period() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / COOKIE_PERIOD
+    }
+
+    fn mac(&self, addr: SocketAddr, period: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        match addr.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets())
+        }
+        mac.update(&addr.port().to_le_bytes());
+        mac.update(&period.to_le_bytes());
+        mac
+    }
+
+    fn bake(&self, addr: SocketAddr, period: u64) -> Cookie {
+        let mut cookie = [0u8; COOKIE_SIZE];
+        cookie.copy_from_slice(&self.mac(addr, period).finalize().into_bytes()[..COOKIE_SIZE]);
+        cookie
+    }
+
+    pub fn issue(&self, addr: SocketAddr) -> Cookie {
+        self.bake(addr, Self::period())
+    }
+
+    pub fn verify(&self, addr: SocketAddr, cookie: &Cookie) -> bool {
+        let period = Self::period();
+        [period, period.saturating_sub(1)].into_iter()
+            .any(|period| self.mac(addr, period).verify_truncated_left(cookie).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CookieJar;
+
+    #[test]
+    fn verify() {
+        let jar = CookieJar::new();
+        let addr = "127.0.0.1:33089".parse().unwrap();
+        let cookie = jar.issue(addr);
+        assert!(jar.verify(addr, &cookie));
+        assert!(!jar.verify("127.0.0.1:33088".parse().unwrap(), &cookie));
+        assert!(!CookieJar::new().verify(addr, &cookie));
+        // Expired
+        let old = jar.bake(addr, CookieJar::period() - 2);
+        assert!(!jar.verify(addr, &old));
+    }
+}
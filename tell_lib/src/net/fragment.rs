@@ -0,0 +1,128 @@
+use std::{collections::HashMap, time::Instant};
+use serde::{Serialize, Deserialize};
+use crate::err::{TResult, TellErr, LibErr};
+use super::adapter::UDP_READ_BUF_SIZE;
+
+// Packets that serialize to more than this are split up
+pub const UDP_FRAGMENT_SIZE: usize = UDP_READ_BUF_SIZE;
+pub const UDP_MAX_FRAGMENTS: usize = 128;
+// Largest serialized packet that can be sent, in fragments
+pub const UDP_MAX_PACKET_SIZE: usize = UDP_FRAGMENT_SIZE * UDP_MAX_FRAGMENTS;
+// Partially received packets are dropped after this many secs
+pub const UDP_FRAGMENT_TIMEOUT: f32 = 5.;
+// Max. bytes buffered for reassembly per peer
+pub const UDP_MAX_REASSEMBLY_BYTES: usize = UDP_MAX_PACKET_SIZE * 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub id: u32,
+    pub index: u16,
+    pub count: u16,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>
+}
+
+struct PartialPacket {
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    size: usize,
+    started: Instant
+}
+
+pub struct Reassembler {
+    partials: HashMap<u32, PartialPacket>,
+    // Sum of all buffered fragments
+    size: usize
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            partials: HashMap::new(), size: 0
+        }
+    }
+
+    // Returns the serialized packet once its last fragment arrived
+    pub fn insert(&mut self, fragment: Fragment) -> TResult<Option<Vec<u8>>> {
+        let Fragment { id, index, count, bytes } = fragment;
+        let (index, count) = (index as usize, count as usize);
+        if count == 0 || count > UDP_MAX_FRAGMENTS || index >= count
+            || bytes.len() > UDP_FRAGMENT_SIZE {
+            return Err(TellErr::Lib(LibErr::InvalidFragment(id)))
+        }
+        self.expire();
+        if self.size + bytes.len() > UDP_MAX_REASSEMBLY_BYTES {
+            return Err(TellErr::Lib(LibErr::ReassemblyLimitReached(self.size)))
+        }
+
+        let partial = self.partials.entry(id).or_insert_with(|| PartialPacket {
+            fragments: vec![None; count], missing: count, size: 0, started: Instant::now()
+        });
+        if partial.fragments.len() != count {
+            return Err(TellErr::Lib(LibErr::InvalidFragment(id)))
+        }
+        if partial.fragments[index].is_some() {
+            // Duplicate
+            return Ok(None)
+        }
+        partial.missing -= 1;
+        partial.size += bytes.len();
+        self.size += bytes.len();
+        partial.fragments[index] = Some(bytes);
+        if partial.missing > 0 {
+            return Ok(None)
+        }
+
+        let partial = self.partials.remove(&id).unwrap();
+        self.size -= partial.size;
+        Ok(Some(partial.fragments.into_iter().flatten().flatten().collect()))
+    }
+
+    // Drop all packets that didn't complete in time
+    pub fn expire(&mut self) {
+        let mut freed = 0;
+        self.partials.retain(|_, partial| {
+            let alive = partial.started.elapsed().as_secs_f32() < UDP_FRAGMENT_TIMEOUT;
+            if !alive {
+                freed += partial.size;
+            }
+            alive
+        });
+        self.size -= freed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fragment, Reassembler, UDP_MAX_FRAGMENTS};
+
+    #[test]
+    fn reassemble() {
+        let mut reassembler = Reassembler::new();
+        let fragment = |index, bytes: &[u8]| Fragment {
+            id: 7, index, count: 3, bytes: bytes.to_vec()
+        };
+        assert_eq!(reassembler.insert(fragment(2, &[5])).unwrap(), None);
+        assert_eq!(reassembler.insert(fragment(0, &[1, 2])).unwrap(), None);
+        assert_eq!(reassembler.insert(fragment(0, &[1, 2])).unwrap(), None);
+        assert_eq!(reassembler.insert(fragment(1, &[3, 4])).unwrap(), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(reassembler.size, 0);
+    }
+
+    #[test]
+    fn invalid() {
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.insert(Fragment {
+            id: 1, index: 3, count: 3, bytes: vec![]
+        }).is_err());
+        assert!(reassembler.insert(Fragment {
+            id: 1, index: 0, count: UDP_MAX_FRAGMENTS as u16 + 1, bytes: vec![]
+        }).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::{collections::BTreeMap, time::Instant};
+use serde::{Serialize, Deserialize};
+use crate::packet::Packet;
+
+// Max. distance between the oldest missing and the newest buffered sequence.
+// Anything beyond is dropped and left to the sender's retransmission.
+pub const RELIABLE_WINDOW_SIZE: u32 = 256;
+
+// Cumulative ack: every seq below `next` has been received. Bit i of `bits`
+// marks `next + 1 + i` as received out of order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {
+    pub next: u32,
+    pub bits: u32
+}
+
+impl Ack {
+    pub fn acks(&self, seq: u32) -> bool {
+        if seq < self.next {
+            true
+        } else if seq > self.next && seq - self.next <= 32 {
+            self.bits & (1 << (seq - self.next - 1)) != 0
+        } else {
+            false
+        }
+    }
+}
+
+struct PendingPacket {
+    bytes: Vec<u8>,
+    sent_at: Instant,
+    retries: u32
+}
+
+pub struct SendWindow {
+    next_seq: u32,
+    pending: BTreeMap<u32, PendingPacket>
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SendWindow {
+    pub fn new() -> Self {
+        Self {
+            next_seq: 0, pending: BTreeMap::new()
+        }
+    }
+
+    pub fn next_seq(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        seq
+    }
+
+    pub fn track(&mut self, seq: u32, bytes: Vec<u8>) {
+        self.pending.insert(seq, PendingPacket {
+            bytes, sent_at: Instant::now(), retries: 0
+        });
+    }
+
+    // Returns the number of packets that were newly acknowledged
+    pub fn ack(&mut self, ack: Ack) -> usize {
+        let before = self.pending.len();
+        self.pending.retain(|&seq, _| !ack.acks(seq));
+        before - self.pending.len()
+    }
+
+    // Collect all packets that haven't been acked in time and restart their timers.
+    // Each of them counts as lost once.
+    pub fn expired(&mut self, timeout: f32) -> Vec<Vec<u8>> {
+        self.pending.values_mut()
+            .filter(|p| p.sent_at.elapsed().as_secs_f32() >= timeout)
+            .map(|p| {
+                p.sent_at = Instant::now();
+                p.retries += 1;
+                p.bytes.clone()
+            })
+            .collect()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Most times a single pending packet was resent
+    pub fn retries(&self) -> u32 {
+        self.pending.values().map(|p| p.retries).max().unwrap_or(0)
+    }
+}
+
+pub struct RecvWindow {
+    next: u32,
+    // Received ahead of `next`. Unordered windows deliver right away and only keep the seq.
+    buffer: BTreeMap<u32, Option<Packet>>,
+    // Received something the remote doesn't know about yet
+    dirty: bool
+}
+
+impl RecvWindow {
+    pub fn new(next: u32) -> Self {
+        Self {
+            next, buffer: BTreeMap::new(), dirty: false
+        }
+    }
+
+    // Window that already consumed `seq` elsewhere, e.g. a connect packet
+    pub fn after(seq: u32) -> Self {
+        Self {
+            next: seq + 1, buffer: BTreeMap::new(), dirty: true
+        }
+    }
+
+    // Accept a sequenced packet and return everything that can now be delivered
+    pub fn recv(&mut self, seq: u32, ordered: bool, packet: Packet) -> Vec<Packet> {
+        // Duplicates still need to be acked again, our last ack might have been lost
+        self.dirty = true;
+        if seq < self.next || seq - self.next >= RELIABLE_WINDOW_SIZE
+            || self.buffer.contains_key(&seq) {
+            return vec![]
+        }
+        let mut ready = vec![];
+        if ordered {
+            self.buffer.insert(seq, Some(packet));
+        } else {
+            self.buffer.insert(seq, None);
+            ready.push(packet);
+        }
+        while let Some(packet) = self.buffer.remove(&self.next) {
+            ready.extend(packet);
+            self.next += 1;
+        }
+        ready
+    }
+
+    pub fn ack(&self) -> Ack {
+        let bits = self.buffer.range(self.next + 1..=self.next + 32)
+            .fold(0, |bits, (&seq, _)| bits | 1 << (seq - self.next - 1));
+        Ack {
+            next: self.next, bits
+        }
+    }
+
+    pub fn take_ack(&mut self) -> Ack {
+        self.dirty = false;
+        self.ack()
+    }
+
+    pub fn needs_ack(&self) -> bool {
+        self.dirty
+    }
+}
+
+// Reliable channel state of a single connection. Both directions are tracked
+// independently, so a stalled ordered channel never blocks another one.
+pub struct Channel {
+    pub send: SendWindow,
+    pub recv: RecvWindow
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self {
+            send: SendWindow::new(), recv: RecvWindow::new(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, packet::{Packet, PacketType}};
+    use super::{RecvWindow, SendWindow};
+
+    fn packet() -> Packet {
+        Packet::new(Id::new("Bob".to_owned()).unwrap(), PacketType::Heartbeat)
+    }
+
+    #[test]
+    fn in_order() {
+        let mut window = RecvWindow::new(0);
+        assert!(window.recv(1, true, packet()).is_empty());
+        assert!(window.recv(3, true, packet()).is_empty());
+        let ack = window.take_ack();
+        assert_eq!((ack.next, ack.bits), (0, 0b101));
+        assert_eq!(window.recv(0, true, packet()).len(), 2);
+        assert_eq!(window.recv(2, true, packet()).len(), 2);
+        // Duplicate
+        assert!(window.recv(1, true, packet()).is_empty());
+        assert!(window.needs_ack());
+        assert_eq!(window.take_ack().next, 4);
+    }
+
+    #[test]
+    fn unordered() {
+        let mut window = RecvWindow::new(0);
+        assert_eq!(window.recv(2, false, packet()).len(), 1);
+        assert_eq!(window.recv(0, false, packet()).len(), 1);
+        // Duplicate
+        assert!(window.recv(2, false, packet()).is_empty());
+        assert_eq!(window.recv(1, false, packet()).len(), 1);
+        assert_eq!(window.take_ack().next, 3);
+    }
+
+    #[test]
+    fn retransmit() {
+        let mut sender = SendWindow::new();
+        let mut receiver = RecvWindow::new(0);
+        for _ in 0..3 {
+            let seq = sender.next_seq();
+            sender.track(seq, vec![seq as u8]);
+        }
+        receiver.recv(0, true, packet());
+        receiver.recv(2, true, packet());
+        assert_eq!(sender.ack(receiver.take_ack()), 2);
+        assert_eq!(sender.expired(0.), vec![vec![1u8]]);
+        assert_eq!(sender.in_flight(), 1);
+        assert_eq!(sender.expired(0.).len(), 1);
+        assert_eq!(sender.retries(), 2);
+        receiver.recv(1, true, packet());
+        sender.ack(receiver.take_ack());
+        assert_eq!(sender.retries(), 0);
+    }
+}
@@ -0,0 +1,357 @@
+use core::fmt;
+use std::str::FromStr;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::{Aead, Payload}};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use x25519_dalek::{StaticSecret, PublicKey as DhPublicKey};
+use crate::err::{TResult, TellErr, LibErr};
+
+// Datagram flags next to the compression ones. Sealed frames are laid out as
+// flag, [responder's ephemeral key], nonce (u64 LE), ciphertext and tag.
+pub const FRAME_SEALED: u8 = 2;
+// Sent by the responder until the initiator proved it derived the same keys
+pub const FRAME_SEALED_KEY: u8 = 3;
+// How far a nonce may lag behind the highest one received so far
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 8;
+const PROTOCOL_NAME: &[u8] = b"tell_NK_25519_ChaChaPoly_SHA256";
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PublicKey(pub [u8; KEY_SIZE]);
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = TellErr;
+
+    // Hex, as displayed
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0u8; KEY_SIZE];
+        if s.len() != KEY_SIZE * 2 || !s.is_ascii() {
+            return Err(TellErr::Lib(LibErr::InvalidKey))
+        }
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| TellErr::Lib(LibErr::InvalidKey))?;
+        }
+        Ok(PublicKey(key))
+    }
+}
+
+// Long-term key of a server. Clients pin its public half.
+#[derive(Clone)]
+pub struct StaticKey(StaticSecret);
+
+impl StaticKey {
+    pub fn generate() -> StaticKey {
+        StaticKey(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_bytes(bytes: [u8; KEY_SIZE]) -> StaticKey {
+        StaticKey(StaticSecret::from(bytes))
+    }
+
+    pub fn to_bytes(&self) -> [u8; KEY_SIZE] {
+        self.0.to_bytes()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(DhPublicKey::from(&self.0).to_bytes())
+    }
+}
+
+impl fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StaticKey({})", self.public_key())
+    }
+}
+
+impl PartialEq for StaticKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key() == other.public_key()
+    }
+}
+
+impl Eq for StaticKey {}
+
+fn dh(secret: &StaticSecret, public: PublicKey) -> TResult<[u8; KEY_SIZE]> {
+    let shared = secret.diffie_hellman(&DhPublicKey::from(public.0));
+    // Low order points would yield a predictable secret
+    if shared.was_contributory() {
+        Ok(shared.to_bytes())
+    } else {
+        Err(TellErr::Lib(LibErr::HandshakeFailed))
+    }
+}
+
+// Client side of the handshake. Like Noise NK: the client knows the server's static key
+// up front and sends an ephemeral one, the server answers with an ephemeral key of its own.
+pub struct Initiator {
+    ephemeral: StaticSecret,
+    server_key: PublicKey
+}
+
+impl Initiator {
+    pub fn new(server_key: PublicKey) -> Initiator {
+        Initiator {
+            ephemeral: StaticSecret::random_from_rng(OsRng), server_key
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(DhPublicKey::from(&self.ephemeral).to_bytes())
+    }
+
+    pub fn finish(&self, reply: PublicKey) -> TResult<SecureSession> {
+        let es = dh(&self.ephemeral, self.server_key)?;
+        let ee = dh(&self.ephemeral, reply)?;
+        Ok(SecureSession::derive(&es, &ee, [self.public_key(), reply, self.server_key], true))
+    }
+}
+
+// Server side of the handshake. Returns the ephemeral key for the client.
+pub fn respond(static_key: &StaticKey, client_key: PublicKey) -> TResult<(PublicKey, SecureSession)> {
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let reply = PublicKey(DhPublicKey::from(&ephemeral).to_bytes());
+    let es = dh(&static_key.0, client_key)?;
+    let ee = dh(&ephemeral, client_key)?;
+    Ok((reply, SecureSession::derive(&es, &ee, [client_key, reply, static_key.public_key()], false)))
+}
+
+// Tracks which nonces were received, like IPsec/WireGuard
+struct ReplayWindow {
+    // Highest nonce so far + 1
+    next: u64,
+    // Bit i marks `next - 1 - i` as received
+    bits: u64
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            next: 0, bits: 0
+        }
+    }
+
+    fn fresh(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            true
+        } else {
+            let age = self.next - 1 - nonce;
+            age < REPLAY_WINDOW_SIZE && self.bits & (1 << age) == 0
+        }
+    }
+
+    fn insert(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce + 1 - self.next;
+            self.bits = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.bits << shift };
+            self.bits |= 1;
+            self.next = nonce + 1;
+        } else {
+            self.bits |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+// Keys and nonces of an established session. Each direction has its own key,
+// so both sides can count their nonces from zero.
+pub struct SecureSession {
+    send: ChaCha20Poly1305,
+    recv: ChaCha20Poly1305,
+    send_nonce: u64,
+    replay: ReplayWindow
+}
+
+impl SecureSession {
+    fn derive(es: &[u8], ee: &[u8], transcript: [PublicKey; 3], initiator: bool) -> SecureSession {
+        let ikm = [es, ee].concat();
+        let info = transcript.iter().flat_map(|key| key.0).collect::<Vec<_>>();
+        let mut okm = [0u8; KEY_SIZE * 2];
+        Hkdf::<Sha256>::new(Some(PROTOCOL_NAME), &ikm).expand(&info, &mut okm)
+            .expect("Valid okm length");
+        let (to_server, to_client) = okm.split_at(KEY_SIZE);
+        let (send, recv) = if initiator {
+            (to_server, to_client)
+        } else {
+            (to_client, to_server)
+        };
+        SecureSession {
+            send: ChaCha20Poly1305::new_from_slice(send).unwrap(),
+            recv: ChaCha20Poly1305::new_from_slice(recv).unwrap(),
+            send_nonce: 0, replay: ReplayWindow::new()
+        }
+    }
+
+    fn nonce(nonce: u64) -> Nonce {
+        let mut bytes = [0u8; 12];
+        bytes[4..].copy_from_slice(&nonce.to_le_bytes());
+        bytes.into()
+    }
+
+    // Everything in front of the ciphertext is authenticated as well
+    fn seal(&mut self, flag: u8, key: Option<PublicKey>, bytes: &[u8]) -> TResult<Vec<u8>> {
+        let nonce = self.send_nonce;
+        self.send_nonce += 1;
+        let mut frame = vec![flag];
+        if let Some(key) = key {
+            frame.extend(key.0);
+        }
+        frame.extend(nonce.to_le_bytes());
+        let sealed = self.send.encrypt(&Self::nonce(nonce), Payload {
+            msg: bytes, aad: &frame
+        }).map_err(|_| TellErr::Lib(LibErr::InvalidSeal))?;
+        frame.extend(sealed);
+        Ok(frame)
+    }
+
+    fn open(&mut self, frame: &[u8]) -> TResult<Vec<u8>> {
+        let prefix = match frame.first() {
+            Some(&FRAME_SEALED_KEY) => 1 + KEY_SIZE,
+            _ => 1
+        };
+        if frame.len() < prefix + NONCE_SIZE {
+            return Err(TellErr::Lib(LibErr::InvalidSeal))
+        }
+        let (aad, sealed) = frame.split_at(prefix + NONCE_SIZE);
+        let nonce = u64::from_le_bytes(aad[prefix..].try_into().unwrap());
+        if !self.replay.fresh(nonce) {
+            return Err(TellErr::Lib(LibErr::InvalidSeal))
+        }
+        let bytes = self.recv.decrypt(&Self::nonce(nonce), Payload {
+            msg: sealed, aad
+        }).map_err(|_| TellErr::Lib(LibErr::InvalidSeal))?;
+        self.replay.insert(nonce);
+        Ok(bytes)
+    }
+}
+
+fn frame_key(frame: &[u8]) -> TResult<PublicKey> {
+    frame.get(1..1 + KEY_SIZE)
+        .map(|key| PublicKey(key.try_into().unwrap()))
+        .ok_or(TellErr::Lib(LibErr::InvalidSeal))
+}
+
+// Encryption state of a connection
+pub enum SecureState {
+    Plain,
+    // Waiting for the server's ephemeral key
+    Initiating(Initiator),
+    // Keys derived, but the client may not have them yet
+    Responding(PublicKey, SecureSession),
+    Established(SecureSession)
+}
+
+impl SecureState {
+    pub fn established(&self) -> bool {
+        matches!(self, SecureState::Established(_))
+    }
+
+    // Seal a (compression framed) datagram, if the session is far enough
+    pub fn seal(&mut self, bytes: Vec<u8>) -> TResult<Vec<u8>> {
+        match self {
+            SecureState::Plain | SecureState::Initiating(_) => Ok(bytes),
+            SecureState::Responding(key, session) => session.seal(FRAME_SEALED_KEY, Some(*key), &bytes),
+            SecureState::Established(session) => session.seal(FRAME_SEALED, None, &bytes)
+        }
+    }
+
+    // Returns the compression framed datagram
+    pub fn open(&mut self, frame: &[u8]) -> TResult<Vec<u8>> {
+        match (frame.first().copied(), &mut *self) {
+            (Some(FRAME_SEALED | FRAME_SEALED_KEY), SecureState::Established(session)) => session.open(frame),
+            (Some(FRAME_SEALED), SecureState::Responding(_, session)) => {
+                let bytes = session.open(frame)?;
+                // Client has the keys, no need to send ours any longer
+                if let SecureState::Responding(_, session) = std::mem::replace(self, SecureState::Plain) {
+                    *self = SecureState::Established(session);
+                }
+                Ok(bytes)
+            },
+            (Some(FRAME_SEALED_KEY), SecureState::Initiating(initiator)) => {
+                let mut session = initiator.finish(frame_key(frame)?)?;
+                let bytes = session.open(frame)?;
+                *self = SecureState::Established(session);
+                Ok(bytes)
+            },
+            (Some(FRAME_SEALED | FRAME_SEALED_KEY), _) => Err(TellErr::Lib(LibErr::InvalidSeal)),
+            (_, SecureState::Established(_)) => Err(TellErr::Lib(LibErr::Unsealed)),
+            // Handshake (still) in plaintext
+            _ => Ok(frame.to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StaticKey, Initiator, SecureState, respond, PublicKey};
+
+    #[test]
+    fn handshake() {
+        let server_key = StaticKey::generate();
+        let initiator = Initiator::new(server_key.public_key());
+        let (reply, session) = respond(&server_key, initiator.public_key()).unwrap();
+        let mut client = SecureState::Initiating(initiator);
+        let mut server = SecureState::Responding(reply, session);
+
+        // Client still speaks plaintext, the server already seals
+        assert_eq!(client.seal(vec![0, 1]).unwrap(), vec![0, 1]);
+        assert_eq!(server.open(&[0, 1]).unwrap(), vec![0, 1]);
+        let sealed = server.seal(vec![0, 2]).unwrap();
+        assert_eq!(client.open(&sealed).unwrap(), vec![0, 2]);
+        assert!(client.established());
+        // Replayed
+        assert!(client.open(&sealed).is_err());
+
+        let sealed = client.seal(vec![0, 3]).unwrap();
+        assert_eq!(server.open(&sealed).unwrap(), vec![0, 3]);
+        assert!(server.established());
+        assert!(server.open(&[0, 4]).is_err());
+
+        let mut tampered = server.seal(vec![0, 5]).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(client.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn wrong_server() {
+        let server_key = StaticKey::generate();
+        // Client expects someone else
+        let initiator = Initiator::new(StaticKey::generate().public_key());
+        let (reply, session) = respond(&server_key, initiator.public_key()).unwrap();
+        let mut server = SecureState::Responding(reply, session);
+        let mut client = SecureState::Initiating(initiator);
+        assert!(client.open(&server.seal(vec![0, 1]).unwrap()).is_err());
+        assert!(!client.established());
+
+        let key = server_key.public_key();
+        assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = super::ReplayWindow::new();
+        for nonce in [3, 1, 70, 8] {
+            assert!(window.fresh(nonce));
+            window.insert(nonce);
+            assert!(!window.fresh(nonce));
+        }
+        // Too old to tell
+        assert!(!window.fresh(3));
+        assert!(window.fresh(69));
+    }
+}
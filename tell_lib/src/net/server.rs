@@ -1,24 +1,60 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Instant, collections::HashSet};
 
 use log::{warn, error, info};
 
-use crate::{id::Id, err::{TResult, TellErr, LibErr}, event::UdpAdapterEvent, packet::{Packet, PacketType, ClientPacket, ServerPacket, DisconnectReason, TargetMode}, net::conn::{UdpConnection, Connection, ConnectionState}};
+use crate::{id::Id, err::{TResult, TellErr, LibErr}, event::UdpAdapterEvent, packet::{Packet, PacketType, ClientPacket, ServerPacket, DisconnectReason, TargetMode, Handshake, Hello, RejectReason, RequestError, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, CONNECT_PADDING}, net::{conn::{UdpConnection, Connection, ConnectionState}, compress::Compression, capability::Capabilities, secure::{self, SecureState}, cookie::CookieJar}, header::PacketHeader, room::Rooms, history::{HistoryStore, MemoryStore, Scope, Record, MessageState, HISTORY_BACKLOG, HISTORY_PAGE, HISTORY_PAGE_BYTES}, mailbox::{Mailboxes, OFFLINE_QUOTA, OFFLINE_EXPIRY}, presence::{Presences, AWAY_AFTER, IDLE_CHECK_INTERVAL}, receipt::{Receipts, Receipt, RECEIPT_INTERVAL, MAX_RECEIPT_BATCH}, transfer::{Relay, MAX_FILE_SIZE, RELAY_RATE}};
 
-use super::adapter::{UdpAdapter, AdapterConfig, SendMode};
+use super::adapter::{UdpAdapter, AdapterConfig, SendMode, CHANNEL_RELIABLE, CHANNEL_UNRELIABLE, CHANNEL_BULK};
 
 pub struct Server {
     id: Id,
-    adapter: UdpAdapter
+    adapter: UdpAdapter,
+    cookies: CookieJar,
+    rooms: Rooms,
+    history: Box<dyn HistoryStore>,
+    // Id of the next message
+    next_message: u64,
+    mailboxes: Mailboxes,
+    presences: Presences,
+    idle_checked: Instant,
+    receipts: Receipts,
+    receipts_flushed: Instant,
+    relay: Relay,
+    // May edit and delete every global message
+    moderators: HashSet<Id>
 }
 
 impl Server {
+    // History is kept in memory only
     pub fn setup(id: Id, config: AdapterConfig) -> TResult<Server> {
+        Self::with_history(id, config, Box::new(MemoryStore::new()))
+    }
+
+    pub fn with_history(id: Id, config: AdapterConfig, history: Box<dyn HistoryStore>) -> TResult<Server> {
         let adapter = UdpAdapter::new(id.clone(), config)?;
+        // Stored messages keep their ids across restarts
+        let next_message = history.last_seq().map_or(0, |seq| seq + 1);
         Ok(Server {
-            id, adapter
+            id, adapter, cookies: CookieJar::new(), rooms: Rooms::new(), history, next_message,
+            mailboxes: Mailboxes::new(OFFLINE_QUOTA, OFFLINE_EXPIRY), presences: Presences::new(AWAY_AFTER),
+            idle_checked: Instant::now(), receipts: Receipts::new(), receipts_flushed: Instant::now(),
+            relay: Relay::new(MAX_FILE_SIZE, RELAY_RATE), moderators: HashSet::new()
         })
     }
 
+    pub fn add_moderator(&mut self, id: Id) {
+        self.moderators.insert(id);
+    }
+
+    pub fn remove_moderator(&mut self, id: &Id) {
+        self.moderators.remove(id);
+    }
+
+    // Max bytes of offered files, and bytes per sec relayed over all transfers
+    pub fn limit_files(&mut self, max_file_size: u64, relay_rate: u64) {
+        self.relay.limit(max_file_size, relay_rate)
+    }
+
     pub fn dispose(self) -> TResult {
         match self.adapter.thread_handle.join() {
             Ok(res) => Ok(res?),
@@ -33,26 +69,282 @@ impl Server {
     }
 
     pub fn send_packet(&self, send_mode: SendMode, packet: ServerPacket) -> TResult {
-        self.adapter.send_command(send_mode, PacketType::Server(packet))
+        self.adapter.send_command(send_mode, CHANNEL_RELIABLE, PacketType::Server(packet))
     } 
 
     pub fn send_broadcast(&self, packet: ServerPacket) -> TResult {
         self.send_packet(SendMode::Broadcast, packet)
     }
 
+    // Addrs of all connected members of a room, except for one
+    fn room_addrs(&self, room: &str, except: Option<&Id>) -> Vec<SocketAddr> {
+        match self.rooms.get(room) {
+            Some(room) => {
+                let _shared_state = self.adapter.shared_state.lock().unwrap();
+                room.members().keys()
+                    .filter(|&id| Some(id) != except)
+                    .filter_map(|id| _shared_state.addr_of(id))
+                    .collect()
+            },
+            None => vec![]
+        }
+    }
+
+    pub fn send_room(&self, room: &str, except: Option<&Id>, packet: ServerPacket) -> TResult {
+        let addrs = self.room_addrs(room, except);
+        if addrs.is_empty() {
+            Ok(())
+        } else {
+            self.send_packet(SendMode::Multicast(addrs), packet)
+        }
+    }
+
+    pub fn send_error(&self, addr: SocketAddr, error: RequestError) -> TResult {
+        self.send_packet(SendMode::Unicast(addr), ServerPacket::Error(error))
+    }
+
+    // Latest page of the scope older than `before`, trimmed to the page byte limit
+    fn send_history(&self, addr: SocketAddr, scope: Scope, before: Option<u64>, limit: usize) -> TResult {
+        let mut records = self.history.page(&scope, before, limit)?;
+        let mut bytes = 0;
+        if let Some(cut) = records.iter().rposition(|record| {
+            bytes += record.text.len();
+            bytes > HISTORY_PAGE_BYTES
+        }) {
+            records.drain(..=cut);
+        }
+        let more = match records.first() {
+            Some(oldest) => !self.history.page(&scope, Some(oldest.seq), 1)?.is_empty(),
+            None => false
+        };
+        self.send_packet(SendMode::Unicast(addr), ServerPacket::History {
+            scope, records, more
+        })
+    }
+
+    // Replies also count towards their root
+    fn store(&mut self, record: Record) -> TResult {
+        let root = record.parent;
+        self.history.append(record)?;
+        if let Some(root) = root {
+            if let Some(mut root) = self.history.get(root)? {
+                root.replies += 1;
+                self.history.update(root)?;
+            }
+        }
+        Ok(())
+    }
+
+    // All replies of the thread the message belongs to, in pages within the byte limit
+    fn send_thread(&self, addr: SocketAddr, id: Id, message: u64) -> TResult {
+        let (scope, root) = match self.history.get(message)? {
+            Some(record) => (record.scope, record.parent.unwrap_or(record.seq)),
+            None => return self.send_error(addr, RequestError::UnknownMessage(message))
+        };
+        if let Scope::Room(room) = &scope {
+            if let Err(e) = self.rooms.joined(room, &id) {
+                return self.send_error(addr, e)
+            }
+        }
+        let mut page = vec![];
+        let mut bytes = 0;
+        for reply in self.history.thread(root)? {
+            if !page.is_empty() && (page.len() >= HISTORY_PAGE || bytes + reply.text.len() > HISTORY_PAGE_BYTES) {
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::Thread {
+                    scope: scope.clone(), root, replies: std::mem::take(&mut page)
+                })?;
+                bytes = 0;
+            }
+            bytes += reply.text.len();
+            page.push(reply);
+        }
+        // Also when empty, so the peer knows it has everything
+        self.send_packet(SendMode::Unicast(addr), ServerPacket::Thread {
+            scope, root, replies: page
+        })
+    }
+
+    // Hand over what was held while the peer was offline and tell the senders
+    fn deliver_held(&mut self, addr: SocketAddr, id: &Id) -> TResult {
+        for held in self.mailboxes.take(id) {
+            self.receipts.acknowledge(held.id, id, Receipt::Sent);
+            self.send_packet(SendMode::Unicast(addr), ServerPacket::Message {
+                id: held.id, source: held.source, target_mode: held.target_mode, text: held.text, parent: None
+            })?;
+        }
+        Ok(())
+    }
+
+    // Edit a stored message, or delete it without text, and tell everyone who can see it
+    fn amend(&mut self, addr: SocketAddr, id: Id, message: u64, text: Option<String>) -> TResult {
+        let mut record = match self.history.get(message)? {
+            Some(record) if record.state != MessageState::Deleted => record,
+            _ => return self.send_error(addr, RequestError::UnknownMessage(message))
+        };
+        // Authors amend their own messages, moderators those of others: the server's in the
+        // open, operators in their rooms
+        let permitted = match &record.scope {
+            Scope::Global if record.source == id || self.moderators.contains(&id) => Ok(()),
+            Scope::Global => Err(RequestError::NotPermitted),
+            // Editing is speaking, muted authors may only delete
+            Scope::Room(room) if record.source == id && text.is_some() => self.rooms.speaker(room, &id).map(|_| ()),
+            Scope::Room(room) => self.rooms.redactor(room, &id, &record.source).map(|_| ())
+        };
+        if let Err(e) = permitted {
+            return self.send_error(addr, e)
+        }
+        let scope = record.scope.clone();
+        let packet = match text {
+            Some(text) => {
+                record.text = text.clone();
+                record.state = MessageState::Edited;
+                ServerPacket::Edited {
+                    scope: scope.clone(), id: message, text
+                }
+            },
+            None => {
+                record.text.clear();
+                record.state = MessageState::Deleted;
+                record.reactions.clear();
+                ServerPacket::Deleted {
+                    scope: scope.clone(), id: message, source: id.clone()
+                }
+            }
+        };
+        info!("[Amend] {:?}{addr} amended message {message}: {:?}.", id, record.state);
+        if let Err(e) = self.history.update(record) {
+            error!("Failed to store amended message {message}: {e}.");
+        }
+        match scope {
+            Scope::Global => self.send_broadcast(packet),
+            Scope::Room(room) => self.send_room(&room, None, packet)
+        }
+    }
+
+    // Everyone who may write where the message is may react to it
+    fn react(&mut self, addr: SocketAddr, id: Id, message: u64, emoji: String, add: bool) -> TResult {
+        let mut record = match self.history.get(message)? {
+            Some(record) if record.state != MessageState::Deleted => record,
+            _ => return self.send_error(addr, RequestError::UnknownMessage(message))
+        };
+        if let Scope::Room(room) = &record.scope {
+            if let Err(e) = self.rooms.speaker(room, &id) {
+                return self.send_error(addr, e)
+            }
+        }
+        let changed = if add {
+            match record.react(emoji, id) {
+                Ok(changed) => changed,
+                Err(e) => return self.send_error(addr, e)
+            }
+        } else {
+            record.unreact(&emoji, &id)
+        };
+        if !changed {
+            return Ok(())
+        }
+        let scope = record.scope.clone();
+        let packet = ServerPacket::Reactions {
+            scope: scope.clone(), id: message, reactions: record.reactions.clone()
+        };
+        if let Err(e) = self.history.update(record) {
+            error!("Failed to store reactions of message {message}: {e}.");
+        }
+        match scope {
+            Scope::Global => self.send_broadcast(packet),
+            Scope::Room(room) => self.send_room(&room, None, packet)
+        }
+    }
+
+    pub fn rooms(&self) -> &Rooms {
+        &self.rooms
+    }
+
     pub fn print_metrics(&self) {
         for conn in self.adapter.shared_state.lock().unwrap().conns.values() {
-            info!("{:?}{:?} metrics: Sent {:?}, Recv {:?}",
-                conn.addr(), conn.id(), conn.send_metrics(), conn.recv_metrics());
+            info!("{:?}{:?} metrics: Sent {:?}, Recv {:?}, Link {:?}",
+                conn.addr(), conn.id(), conn.send_metrics(), conn.recv_metrics(), conn.link_metrics());
         }
     }
 
     pub fn poll(&mut self) -> TResult {
         //let _shared_state = self.adapter.shared_state.lock().unwrap();
-        Ok(for ev in self.adapter.flush_events().into_iter() {
+        for ev in self.adapter.flush_events().into_iter() {
             info!("Server event: {:?}.", ev);
             self.handle_event(ev)?;
-        })
+        }
+        if self.idle_checked.elapsed().as_secs_f32() >= IDLE_CHECK_INTERVAL {
+            self.idle_checked = Instant::now();
+            self.check_idle()?;
+            for (transfer, source, target) in self.relay.expire() {
+                info!("[Transfer] #{transfer} expired.");
+                let addrs = {
+                    let _shared_state = self.adapter.shared_state.lock().unwrap();
+                    [source, target].iter().filter_map(|id| _shared_state.addr_of(id)).collect::<Vec<_>>()
+                };
+                self.send_packet(SendMode::Multicast(addrs), ServerPacket::TransferCancelled(transfer, self.id.clone()))?;
+            }
+            // Bans, mutes and held messages run out whether the peer is still around or not
+            self.rooms.expire();
+            let _shared_state = self.adapter.shared_state.lock().unwrap();
+            self.mailboxes.expire_all(|id| _shared_state.addr_of(id).is_some());
+        }
+        if self.receipts_flushed.elapsed().as_secs_f32() >= RECEIPT_INTERVAL {
+            self.receipts_flushed = Instant::now();
+            self.flush_receipts()?;
+        }
+        self.relay_chunks()
+    }
+
+    // Whatever the relay rate allows, on their own channel
+    fn relay_chunks(&mut self) -> TResult {
+        for (target, chunk) in self.relay.relay().into_iter() {
+            let addr = self.adapter.shared_state.lock().unwrap().addr_of(&target);
+            if let Some(addr) = addr {
+                self.adapter.send_command(SendMode::Unicast(addr), CHANNEL_BULK,
+                    PacketType::Server(ServerPacket::Chunk(chunk)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Receipts are gathered and sent to each sender in a few packets, instead of one per recipient and message
+    fn flush_receipts(&mut self) -> TResult {
+        for (sender, updates) in self.receipts.take().into_iter() {
+            let addr = match self.adapter.shared_state.lock().unwrap().addr_of(&sender) {
+                Some(addr) => addr,
+                // Gone, the sender has to ask again
+                None => continue
+            };
+            for batch in updates.chunks(MAX_RECEIPT_BATCH) {
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::Receipts(batch.to_vec()))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Peers we heard nothing but heartbeats from for long show as away. They're back with the next payload they handle.
+    fn check_idle(&mut self) -> TResult {
+        let away_after = self.presences.away_after();
+        let idle = self.adapter.shared_state.lock().unwrap().conns.values()
+            .filter(|conn| conn.conn_state() == ConnectionState::Established)
+            .filter(|conn| conn.recv_metrics().last_transfer.elapsed().as_secs_f32() >= away_after)
+            .filter_map(|conn| conn.id().cloned())
+            .collect::<Vec<_>>();
+        for id in idle.into_iter() {
+            self.refresh_presence(id, away_after)?;
+        }
+        Ok(())
+    }
+
+    fn refresh_presence(&mut self, id: Id, idle: f32) -> TResult {
+        match self.presences.refresh(&id, idle) {
+            Some(presence) => {
+                info!("[Presence] {:?} is {:?}.", id, presence.status);
+                self.send_broadcast(ServerPacket::Presence(id, presence))
+            },
+            None => Ok(())
+        }
     }
 
     fn handle_event(&mut self, ev: UdpAdapterEvent) -> TResult {
@@ -62,8 +354,7 @@ impl Server {
                     header, payload
                 } = packet;
                 if let Some(client_packet) = payload.client() {
-                    self.handle_connect_event(addr,
-                        header.source().clone(), client_packet)
+                    self.handle_connect_event(addr, header, client_packet)
                 } else {
                     error!("Recv invalid packet type: server/heartbeat.");
                     // TODO: error handling?
@@ -74,12 +365,30 @@ impl Server {
             UdpAdapterEvent::PeerDisconnect(addr, id, reason) => {
                 self.handle_disconnect_event(addr, id, reason)
             },
-            UdpAdapterEvent::Payload(addr, packet) => {
-                let Packet {
-                    header, payload
-                } = packet;
-                if let Some(client_packet) = payload.client() {
-                    self.handle_payload_event(addr, header.source().clone(), client_packet)
+            UdpAdapterEvent::ProtocolViolation(addr, id, violation, strikes) => {
+                // The adapter already ignores (and disconnects) repeat offenders
+                warn!("[Violation] {:?}{addr} violated protocol: {:?} ({strikes} strikes).", id, violation);
+                Ok(())
+            },
+            UdpAdapterEvent::Payload(addr, _channel, packet) => {
+                // The adapter dropped packets whose source didn't match, but only the
+                // connection's id is authoritative
+                let id = match self.adapter.shared_state.lock().unwrap().conns.get(&addr)
+                    .and_then(|conn| conn.id().cloned()) {
+                    Some(id) => id,
+                    // Disconnected since
+                    None => {
+                        warn!("Recv {:?} from unconnected peer {addr}.", packet.payload);
+                        return Ok(())
+                    }
+                };
+                if let Some(client_packet) = packet.payload.client() {
+                    self.handle_payload_event(addr, id.clone(), client_packet)?;
+                    // Just heard from it, so it's not idle. Gone already if it disconnected.
+                    if self.adapter.shared_state.lock().unwrap().conns.contains_key(&addr) {
+                        self.refresh_presence(id, 0.)?;
+                    }
+                    Ok(())
                 } else {
                     Err(TellErr::Lib(LibErr::InvalidPacketType("Expected client type packet".to_owned())))
                 }
@@ -87,17 +396,118 @@ impl Server {
         }
     }
 
-    fn handle_connect_event(&mut self, addr: SocketAddr, id: Id, packet: ClientPacket) -> TResult {
+    fn handle_connect_event(&mut self, addr: SocketAddr, header: PacketHeader, packet: ClientPacket) -> TResult {
+        let id = header.source().clone();
         match packet {
-            ClientPacket::Connect => {
-                info!("[Connect] {:?}{addr} connected to the server!", id);
+            ClientPacket::Connect(version, hello) => {
+                if hello.len() < CONNECT_PADDING {
+                    warn!("[Connect] Ignored unpadded connect from {:?}{addr}.", id);
+                    return Ok(())
+                }
+                let config = self.adapter.config();
+                if header.codec() != config.codec {
+                    warn!("[Connect] Rejected {:?}{addr}: Uses codec {:?}, expected {:?}.", id, header.codec(), config.codec);
+                    return self.send_packet(SendMode::Unicast(addr),
+                        ServerPacket::ConnectRejected(RejectReason::CodecMismatch(config.codec)))
+                }
+                if version < MIN_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+                    warn!("[Connect] Rejected {:?}{addr}: Uses protocol version {version}, expected {MIN_PROTOCOL_VERSION}-{PROTOCOL_VERSION}.", id);
+                    return self.send_packet(SendMode::Unicast(addr), ServerPacket::ConnectRejected(
+                        RejectReason::VersionMismatch(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)))
+                }
+                let hello = match Hello::decode(&hello) {
+                    Ok(hello) if hello.padding.len() >= CONNECT_PADDING => hello,
+                    Ok(_) => {
+                        warn!("[Connect] Ignored unpadded connect from {:?}{addr}.", id);
+                        return Ok(())
+                    },
+                    Err(e) => {
+                        warn!("[Connect] Ignored connect from {:?}{addr} with malformed hello: {e}.", id);
+                        return Ok(())
+                    }
+                };
+                let mut _shared_state = self.adapter.shared_state.lock().unwrap();
+                if _shared_state.conns.contains_key(&addr) {
+                    // Retransmitted connect that overtook our accept
+                    return Ok(())
+                }
+                if !hello.cookie.is_some_and(|cookie| self.cookies.verify(addr, &cookie)) {
+                    // Nothing is allocated until the source proved it can receive at its address
+                    drop(_shared_state);
+                    return self.send_packet(SendMode::Unicast(addr), ServerPacket::Retry(self.cookies.issue(addr)))
+                }
+                if _shared_state.name_taken(id.name()) {
+                    drop(_shared_state);
+                    warn!("[Connect] Rejected {:?}{addr}: Name is taken.", id);
+                    return self.send_packet(SendMode::Unicast(addr), ServerPacket::ConnectRejected(RejectReason::NameTaken))
+                }
                 // UdpConnection::approving immediately sets connection state to established
-                self.adapter.shared_state.lock().unwrap().add_conn(
-                    UdpConnection::incoming(addr, id.clone()))?;
-                self.send_broadcast(ServerPacket::PeerConnected(id))
+                let mut conn = UdpConnection::incoming(addr, &header);
+                let (mut ours, mut offered) = (config.capabilities, hello.capabilities);
+                if config.static_key.is_none() {
+                    ours = ours.without(Capabilities::ENCRYPTION);
+                }
+                if hello.key.is_none() {
+                    offered = offered.without(Capabilities::ENCRYPTION);
+                }
+                let capabilities = ours.negotiate(offered);
+                if let (true, Some(static_key), Some(key)) = (capabilities.contains(Capabilities::ENCRYPTION),
+                    config.static_key.as_ref(), hello.key) {
+                    // Everything from here on is sealed, the client derives the keys from the first reply
+                    match secure::respond(static_key, key) {
+                        Ok((reply, session)) => conn.set_secure(SecureState::Responding(reply, session)),
+                        Err(e) => {
+                            warn!("[Connect] Handshake with {:?}{addr} failed: {e}.", id);
+                            return Ok(())
+                        }
+                    }
+                }
+                let compression = if capabilities.contains(Capabilities::COMPRESSION) {
+                    Compression::negotiate(&hello.compression, &config.compression)
+                } else {
+                    Compression::None
+                };
+                let session = _shared_state.next_session();
+                conn.set_capabilities(capabilities);
+                conn.set_compression(compression);
+                // Peer uses the session once it got the handshake, we follow when we see it do so
+                conn.set_session(session, false);
+                match _shared_state.add_conn(conn) {
+                    Err(TellErr::Lib(LibErr::MaxConnectionsReached(conns))) => {
+                        drop(_shared_state);
+                        warn!("[Connect] Rejected {:?}{addr}: Server is full ({conns} connections).", id);
+                        return self.send_packet(SendMode::Unicast(addr), ServerPacket::ConnectRejected(RejectReason::ServerFull))
+                    },
+                    result => result?
+                }
+                drop(_shared_state);
+                info!("[Connect] {:?}{addr} connected to the server! Capabilities: {:?}.", id, capabilities);
+                let mailbox = self.mailboxes.open(&id, hello.mailbox);
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::ConnectAccepted(Handshake {
+                    session, version, capabilities, compression, mailbox
+                }))?;
+                let others = self.adapter.shared_state.lock().unwrap().conn_addrs().into_iter()
+                    .filter(|&other| other != addr)
+                    .collect::<Vec<_>>();
+                self.send_packet(SendMode::Multicast(others), ServerPacket::PeerConnected(id.clone()))?;
+                self.send_history(addr, Scope::Global, None, HISTORY_BACKLOG)?;
+                if mailbox.is_some() {
+                    self.deliver_held(addr, &id)?;
+                } else {
+                    warn!("[Mailbox] {:?}{addr} didn't prove the mailbox is its own.", id);
+                }
+                // Files it was sending before it went away
+                for (transfer, from) in self.relay.resume(&id).into_iter() {
+                    self.send_packet(SendMode::Unicast(addr), ServerPacket::Accepted(transfer, from))?;
+                }
+                Ok(())
             },
-            p @ _ => Err(TellErr::Lib(
-                LibErr::InvalidPacketType(format!("{:?}", p))))
+            // Reliable packets sent right after the connect may overtake it. They are not
+            // acked yet, so the client will retransmit them once the connection exists.
+            p @ _ => {
+                warn!("Recv {:?} from unconnected peer {:?}{addr}.", p, id);
+                Ok(())
+            }
         }
     }
 
@@ -105,73 +515,357 @@ impl Server {
         if let Some(conn) = self.adapter.shared_state.lock().unwrap()
             .remove_conn(addr) {
             info!("[Disconnect] {:?}{addr} disconnected. Reason: {:?}.", id, reason);
-            info!("Disconnected peer ({:?}) metrics: {:?}, {:?}.", conn.conn_state(), conn.send_metrics(), conn.recv_metrics());
+            info!("Disconnected peer ({:?}) metrics: {:?}, {:?}, {:?}.", conn.conn_state(),
+                conn.send_metrics(), conn.recv_metrics(), conn.link_metrics());
+            if let Some(id) = conn.id() {
+                // Members learn about it through the disconnect below
+                self.rooms.leave_all(id);
+                self.presences.remove(id);
+                self.relay.disconnected(id);
+            }
             if conn.conn_state() == ConnectionState::Established {
                 // If connection wasn't established, other clients might not now ID
                 self.send_broadcast(ServerPacket::PeerDisconnected(id.unwrap(), reason))?;
             }
-            Ok(())
         } else {
-            Err(TellErr::Lib(LibErr::PeerNotConnected(addr)))
+            // Said goodbye and timed out, or the like
+            info!("[Disconnect] {:?}{addr} is gone already. Reason: {:?}.", id, reason);
         }
+        Ok(())
     }
 
     fn handle_payload_event(&mut self, addr: SocketAddr, id: Id, packet: ClientPacket) -> TResult {
         match packet {
             ClientPacket::Disconnect => self.handle_disconnect_event(addr, Some(id), DisconnectReason::Manual),
-            ClientPacket::Message(target_mode, text) => {
+            // First connect of the cookie exchange, still opens the reliable channel
+            ClientPacket::Connect(..) => Ok(()),
+            ClientPacket::Message(target_mode, text, parent) => {
+                if matches!(target_mode, TargetMode::Room(_)) && !self.rooms_enabled(addr) {
+                    return self.send_error(addr, RequestError::RoomsUnsupported)
+                }
+                // Replies hang off the root of the thread, in the same target
+                let root = match parent {
+                    Some(parent) => match self.history.get(parent)? {
+                        Some(record) if record.state != MessageState::Deleted => {
+                            let same_target = match (&record.scope, &target_mode) {
+                                (Scope::Global, TargetMode::Broadcast) => true,
+                                (Scope::Room(room), TargetMode::Room(target)) => room == target,
+                                _ => false
+                            };
+                            if !same_target {
+                                return self.send_error(addr, RequestError::ThreadMismatch(parent))
+                            }
+                            Some(record.parent.unwrap_or(record.seq))
+                        },
+                        _ => return self.send_error(addr, RequestError::UnknownMessage(parent))
+                    },
+                    None => None
+                };
                 let _shared_state = self.adapter.shared_state.lock().unwrap();
+                // Addressed peers and their addrs, if online. The sender learns what happened to each.
+                let recipients = match &target_mode {
+                    TargetMode::Multicast(ids) => ids.iter().map(|id| (id.clone(), _shared_state.addr_of(id))).collect(),
+                    TargetMode::Unicast(target) => vec![(target.clone(), _shared_state.addr_of(target))],
+                    _ => vec![]
+                };
                 let send_mode = match &target_mode {
                     TargetMode::Broadcast => SendMode::Broadcast,
-                    // For multicast, filter out all established connections and look up their addrs
-                    TargetMode::Multicast(ids) => SendMode::Multicast(
-                        _shared_state.conns.values()
-                        .filter_map(|conn| {
-                            if let Some(id) = conn.id() {
-                                if ids.contains(id) {
-                                    return Some(conn.addr())
-                                }
-                            }
-                            None
-                        })
-                        .collect::<Vec<_>>()),
-                    TargetMode::Unicast(id) => todo!(),
+                    TargetMode::Multicast(_) | TargetMode::Unicast(_) => SendMode::Multicast(
+                        recipients.iter().filter_map(|&(_, addr)| addr).collect()),
+                    TargetMode::Room(room) => match self.rooms.speaker(room, &id) {
+                        Ok(room) => SendMode::Multicast(room.members().keys()
+                            .filter_map(|id| _shared_state.addr_of(id)).collect()),
+                        Err(e) => {
+                            drop(_shared_state);
+                            return self.send_error(addr, e)
+                        }
+                    }
                 };
+                drop(_shared_state);
+                let message = self.next_message;
+                self.next_message += 1;
+                let scope = match &target_mode {
+                    TargetMode::Broadcast => Some(Scope::Global),
+                    TargetMode::Room(room) => Some(Scope::Room(room.clone())),
+                    _ => None
+                };
+                if let Some(scope) = scope {
+                    let mut record = Record::new(message, scope, id.clone(), text.clone());
+                    record.parent = root;
+                    // Still relay it, history is best effort
+                    if let Err(e) = self.store(record) {
+                        error!("Failed to store message of {:?}{addr}: {e}.", id);
+                    }
+                }
                 self.send_packet(send_mode, ServerPacket::Message {
-                    source: id, target_mode, text
-                })
+                    id: message, source: id.clone(), target_mode: target_mode.clone(), text: text.clone(), parent: root
+                })?;
+                if matches!(target_mode, TargetMode::Broadcast | TargetMode::Room(_)) {
+                    return Ok(())
+                }
+                let (mut receipts, mut errors) = (vec![], vec![]);
+                for (target, target_addr) in recipients.into_iter() {
+                    let receipt = match target_addr {
+                        Some(_) => Receipt::Sent,
+                        None => match self.mailboxes.hold(&target, message, id.clone(), target_mode.clone(), text.clone()) {
+                            Ok(()) => {
+                                info!("[Mailbox] Holding message of {:?}{addr} for {:?}.", id, target);
+                                Receipt::Queued
+                            },
+                            Err(e) => {
+                                warn!("[Mailbox] Dropped message of {:?}{addr}: {:?}.", id, e);
+                                errors.push(e);
+                                Receipt::Failed
+                            }
+                        }
+                    };
+                    receipts.push((target, receipt));
+                }
+                self.receipts.track(message, id, receipts.clone());
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::Sent {
+                    id: message, receipts
+                })?;
+                // Why it failed
+                for e in errors.into_iter() {
+                    self.send_error(addr, e)?;
+                }
+                Ok(())
             },
             ClientPacket::RequestPeers => {
-                let ids = self.adapter.shared_state
+                let peers = self.adapter.shared_state
                     .lock().unwrap().conns.values()
                     .filter_map(|conn| {
-                        conn.id().cloned()
+                        conn.id().map(|id| (id.clone(), self.presences.get(id)))
                 }).collect::<Vec<_>>();
-                self.send_packet(SendMode::Unicast(addr), ServerPacket::RequestReply(ids))
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::RequestReply(peers))
+            },
+            ClientPacket::RequestHistory { scope, before } => {
+                if let Scope::Room(room) = &scope {
+                    if let Err(e) = self.rooms.joined(room, &id) {
+                        return self.send_error(addr, e)
+                    }
+                }
+                self.send_history(addr, scope, before, HISTORY_PAGE)
+            },
+            ClientPacket::Edit(message, text) => self.amend(addr, id, message, Some(text)),
+            ClientPacket::Delete(message) => self.amend(addr, id, message, None),
+            ClientPacket::Typing(mut target_mode) => {
+                // Nobody waits for these, too frequent or misdirected ones are dropped quietly
+                if !self.presences.typing(&id) {
+                    return Ok(())
+                }
+                let _shared_state = self.adapter.shared_state.lock().unwrap();
+                // Only connected peers the typist could write to, once each. The list is relayed too.
+                if let TargetMode::Multicast(ids) = &mut target_mode {
+                    let mut seen = HashSet::new();
+                    ids.retain(|other| other != &id && _shared_state.addr_of(other).is_some() && seen.insert(other.clone()));
+                }
+                let addrs = match &target_mode {
+                    TargetMode::Broadcast => _shared_state.conn_ids.iter()
+                        .filter(|&(other, _)| other != &id)
+                        .map(|(_, &addr)| addr)
+                        .collect(),
+                    TargetMode::Multicast(ids) => ids.iter().filter_map(|id| _shared_state.addr_of(id)).collect(),
+                    TargetMode::Unicast(target) => _shared_state.addr_of(target).into_iter()
+                        .filter(|_| target != &id)
+                        .collect(),
+                    TargetMode::Room(room) => match self.rooms.speaker(room, &id) {
+                        Ok(room) => room.members().keys()
+                            .filter(|&member| member != &id)
+                            .filter_map(|member| _shared_state.addr_of(member))
+                            .collect(),
+                        Err(_) => vec![]
+                    }
+                };
+                drop(_shared_state);
+                if addrs.is_empty() {
+                    Ok(())
+                } else {
+                    self.adapter.send_command(SendMode::Multicast(addrs), CHANNEL_UNRELIABLE,
+                        PacketType::Server(ServerPacket::Typing(id, target_mode)))
+                }
+            },
+            ClientPacket::SetPresence(presence) => {
+                match self.presences.choose(id, presence) {
+                    // Announced once handled
+                    Ok(()) => Ok(()),
+                    Err(e) => self.send_error(addr, e)
+                }
+            },
+            ClientPacket::Receipts { delivered, read } => {
+                // Unknown or stale ones are just ignored
+                for message in delivered.into_iter() {
+                    self.receipts.acknowledge(message, &id, Receipt::Delivered);
+                }
+                for message in read.into_iter() {
+                    self.receipts.acknowledge(message, &id, Receipt::Read);
+                }
+                Ok(())
+            },
+            ClientPacket::RequestThread(message) => self.send_thread(addr, id, message),
+            p @ (ClientPacket::Offer(..) | ClientPacket::Accept(..) | ClientPacket::Chunk(_)
+                | ClientPacket::ChunkAck(..) | ClientPacket::CancelTransfer(_)) =>
+                self.handle_transfer_event(addr, id, p),
+            ClientPacket::React(message, emoji) => self.react(addr, id, message, emoji, true),
+            ClientPacket::Unreact(message, emoji) => self.react(addr, id, message, emoji, false),
+            p @ (ClientPacket::Join(_) | ClientPacket::Leave(_) | ClientPacket::ListRooms
+                | ClientPacket::SetTopic(..) | ClientPacket::Moderate(..)) =>
+                self.handle_room_event(addr, id, p)
+        }
+    }
+
+    // Files are relayed chunk by chunk and only between connected peers
+    fn handle_transfer_event(&mut self, addr: SocketAddr, id: Id, packet: ClientPacket) -> TResult {
+        match packet {
+            ClientPacket::Offer(target, file) => {
+                let target_addr = match self.adapter.shared_state.lock().unwrap().addr_of(&target) {
+                    Some(target_addr) => target_addr,
+                    None => return self.send_error(addr, RequestError::PeerOffline(target))
+                };
+                match self.relay.offer(id.clone(), target.clone(), &file) {
+                    Ok(transfer) => {
+                        info!("[Transfer] #{transfer} {:?}{addr} offers {:?} ({}b) to {:?}.", id, file.name, file.size, target);
+                        self.send_packet(SendMode::Multicast(vec![target_addr, addr]), ServerPacket::Offer {
+                            id: transfer, source: id, target, file
+                        })
+                    },
+                    Err(e) => self.send_error(addr, e)
+                }
+            },
+            ClientPacket::Accept(transfer, from) => match self.relay.accept(transfer, &id, from) {
+                Ok(source) => {
+                    info!("[Transfer] #{transfer} {:?}{addr} wants chunks from {from} on.", id);
+                    let source_addr = self.adapter.shared_state.lock().unwrap().addr_of(&source);
+                    match source_addr {
+                        Some(source_addr) => self.send_packet(SendMode::Unicast(source_addr), ServerPacket::Accepted(transfer, from)),
+                        // Told once it's back
+                        None => Ok(())
+                    }
+                },
+                Err(e) => self.send_error(addr, e)
+            },
+            ClientPacket::Chunk(chunk) => {
+                let (transfer, index) = (chunk.transfer, chunk.index);
+                if !self.relay.chunk(&id, chunk) {
+                    warn!("[Transfer] #{transfer} Dropped chunk {index} of {:?}{addr}.", id);
+                }
+                Ok(())
+            },
+            ClientPacket::ChunkAck(transfer, next) => match self.relay.ack(transfer, &id, next) {
+                Some((source, complete)) => {
+                    if complete {
+                        info!("[Transfer] #{transfer} {:?}{addr} got everything.", id);
+                    }
+                    let source_addr = self.adapter.shared_state.lock().unwrap().addr_of(&source);
+                    match source_addr {
+                        Some(source_addr) => self.send_packet(SendMode::Unicast(source_addr), ServerPacket::ChunkAck(transfer, next)),
+                        None => Ok(())
+                    }
+                },
+                None => self.send_error(addr, RequestError::UnknownTransfer(transfer))
+            },
+            ClientPacket::CancelTransfer(transfer) => match self.relay.cancel(transfer, &id) {
+                Some(other) => {
+                    info!("[Transfer] #{transfer} Cancelled by {:?}{addr}.", id);
+                    let other_addr = self.adapter.shared_state.lock().unwrap().addr_of(&other);
+                    match other_addr {
+                        Some(other_addr) => self.send_packet(SendMode::Unicast(other_addr), ServerPacket::TransferCancelled(transfer, id)),
+                        None => Ok(())
+                    }
+                },
+                None => self.send_error(addr, RequestError::UnknownTransfer(transfer))
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn rooms_enabled(&self, addr: SocketAddr) -> bool {
+        self.adapter.shared_state.lock().unwrap().conns.get(&addr)
+            .is_some_and(|conn| conn.has(Capabilities::ROOMS))
+    }
+
+    fn handle_room_event(&mut self, addr: SocketAddr, id: Id, packet: ClientPacket) -> TResult {
+        if !self.rooms_enabled(addr) {
+            return self.send_error(addr, RequestError::RoomsUnsupported)
+        }
+        match packet {
+            ClientPacket::Join(room) => {
+                let rejoined = self.rooms.get(&room).is_some_and(|r| r.contains(&id));
+                let (topic, topic_locked, members) = match self.rooms.join(&room, id.clone()) {
+                    Ok(joined) => (joined.topic().cloned(), joined.topic_locked(),
+                        joined.members().iter().map(|(id, &role)| (id.clone(), role)).collect()),
+                    Err(e) => return self.send_error(addr, e)
+                };
+                if !rejoined {
+                    info!("[Room] {:?} joined {room}.", id);
+                    self.send_room(&room, Some(&id), ServerPacket::PeerJoined(room.clone(), id.clone()))?;
+                }
+                self.send_packet(SendMode::Unicast(addr), ServerPacket::Joined {
+                    room: room.clone(), topic, topic_locked, members
+                })?;
+                self.send_history(addr, Scope::Room(room), None, HISTORY_BACKLOG)
+            },
+            ClientPacket::Leave(room) => match self.rooms.leave(&room, &id) {
+                Ok(()) => {
+                    info!("[Room] {:?} left {room}.", id);
+                    self.send_room(&room, None, ServerPacket::PeerLeft(room.clone(), id.clone(), DisconnectReason::Manual))?;
+                    self.send_packet(SendMode::Unicast(addr), ServerPacket::PeerLeft(room, id, DisconnectReason::Manual))
+                },
+                Err(e) => self.send_error(addr, e)
+            },
+            ClientPacket::ListRooms => self.send_packet(SendMode::Unicast(addr), ServerPacket::RoomList(self.rooms.list())),
+            ClientPacket::SetTopic(room, topic) => match self.rooms.set_topic(&room, &id, topic) {
+                Ok(()) => {
+                    let topic = self.rooms.get(&room).and_then(|r| r.topic().cloned());
+                    info!("[Room] {:?} set topic of {room} to {:?}.", id, topic);
+                    self.send_room(&room, None, ServerPacket::Topic {
+                        room: room.clone(), source: id, topic
+                    })
+                },
+                Err(e) => self.send_error(addr, e)
+            },
+            ClientPacket::Moderate(room, action) => {
+                // The target learns about it as well, even when it's removed
+                let addrs = self.room_addrs(&room, None);
+                let removed = action.target()
+                    .filter(|&target| action.removes() && self.rooms.get(&room).is_some_and(|r| r.contains(target)))
+                    .cloned();
+                if let Err(e) = self.rooms.moderate(&room, &id, &action) {
+                    warn!("[Room] {:?} failed to moderate {room}: {:?}.", id, e);
+                    return self.send_error(addr, e)
+                }
+                info!("[Room] {:?} moderated {room}: {:?}.", id, action);
+                self.send_packet(SendMode::Multicast(addrs.clone()), ServerPacket::Moderation {
+                    room: room.clone(), source: id, action
+                })?;
+                match removed {
+                    Some(target) => self.send_packet(SendMode::Multicast(addrs),
+                        ServerPacket::PeerLeft(room, target, DisconnectReason::Kicked)),
+                    None => Ok(())
+                }
             },
             p @ _ => Err(TellErr::Lib(
-                    LibErr::InvalidPacketType(format!("Expected disconnect/message/request. Recv: {:?}", p))))
+                LibErr::InvalidPacketType(format!("Expected room request. Recv: {:?}", p))))
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{time::Duration, net::UdpSocket};
 
-    use crate::{id::Id, net::{adapter::AdapterConfig, client::Client}, packet::TargetMode};
+    use crate::{id::Id, net::{adapter::{AdapterConfig, UDP_MAX_STRIKES}, client::Client, compress::Compression, capability::Capabilities, conn::Connection, secure::StaticKey, cookie::COOKIE_SIZE}, packet::{Packet, TargetMode, RejectReason, DisconnectReason, RequestError, PacketType, ClientPacket, ServerPacket, Hello, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, CONNECT_PADDING}, codec::CodecKind, err::{TellErr, LibErr}, builder::{PacketBuilder, PacketReader}, room::{Role, ModAction}, history::{Scope, ChatEntry, MessageState}, presence::{Presences, Presence, Status}, receipt::Receipt, transfer::{TransferState, TransferEvent, FILE_CHUNK_SIZE}, net::reliable::Ack};
     use super::Server;
 
     #[test]
     fn connect() {
         simple_logger::init().unwrap();
         let mut server = Server::setup(
-            Id::new("Chef".to_owned()).unwrap(), AdapterConfig {
-                port: 22089, max_conns: 3
-            }).unwrap();
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22089, 3)).unwrap();
         let mut client = Client::new(
             Id::new("Some dude".to_owned()).unwrap(), 33089).unwrap();
-        client.connect(format!("127.0.0.1:22089").parse().unwrap()).unwrap();
+        client.connect("127.0.0.1:22089".parse().unwrap(), None).unwrap();
         for _ in 0..1000 {
             server.poll().unwrap();
             client.poll().unwrap();
@@ -185,12 +879,11 @@ mod tests {
     fn message() {
         simple_logger::init().unwrap();
         let mut server = Server::setup(
-            Id::new("Chef".to_owned()).unwrap(), AdapterConfig {
-                port: 22089, max_conns: 3
-            }).unwrap();
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22089, 3)).unwrap();
         let mut client = Client::new(
             Id::new("Some dude".to_owned()).unwrap(), 33089).unwrap();
-        client.connect(format!("127.0.0.1:22089").parse().unwrap()).unwrap();
+        client.connect("127.0.0.1:22089".parse().unwrap(), None).unwrap();
         for _ in 0..1000 {
             server.poll().unwrap();
             client.message(TargetMode::Broadcast, "Hello world!".to_owned()).unwrap();
@@ -200,4 +893,815 @@ mod tests {
         server.shutdown().unwrap();
         client.shutdown().unwrap();
     }
+
+    #[test]
+    fn malformed() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22090, 3)).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:33090").unwrap();
+        for _ in 0..UDP_MAX_STRIKES {
+            sock.send_to(&[0xc1, 0xff, 0x00], "127.0.0.1:22090").unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(500));
+        server.poll().unwrap();
+        assert!(!server.adapter.thread_handle.is_finished());
+        assert!(server.adapter.shared_state.lock().unwrap()
+            .ignored(sock.local_addr().unwrap()));
+        // Never connected, nothing to do
+        server.handle_disconnect_event(sock.local_addr().unwrap(), None, DisconnectReason::Timeout).unwrap();
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn codec_mismatch() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22091, 3)).unwrap();
+        let mut config = AdapterConfig::new(33091, 1);
+        config.codec = CodecKind::Json;
+        let mut client = Client::with_config(
+            Id::new("Some dude".to_owned()).unwrap(), config).unwrap();
+        client.connect("127.0.0.1:22091".parse().unwrap(), None).unwrap();
+        let mut rejected = None;
+        for _ in 0..200 {
+            server.poll().unwrap();
+            if let Err(e) = client.poll() {
+                rejected = Some(e);
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(rejected, Some(TellErr::Lib(LibErr::ConnectionRejected(
+            RejectReason::CodecMismatch(CodecKind::MessagePack))))));
+        assert!(server.adapter.shared_state.lock().unwrap().conns.is_empty());
+        server.shutdown().unwrap();
+        client.shutdown().unwrap();
+    }
+
+    // Send from a plain socket and let the server handle it. Returns the request size.
+    fn raw_send(server: &mut Server, sock: &UdpSocket, builder: &PacketBuilder, packet: ClientPacket) -> usize {
+        let mut bytes = vec![0];
+        bytes.extend(builder.serialize(PacketType::Client(packet)).unwrap());
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        sock.send_to(&bytes, format!("127.0.0.1:{}", server.adapter.config().port)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        server.poll().unwrap();
+        bytes.len()
+    }
+
+    // Acks everything it receives, a stalled congestion window would hold back later packets
+    fn raw_recv(sock: &UdpSocket, builder: &PacketBuilder) -> (usize, Packet) {
+        let mut buf = [0u8; 1024];
+        loop {
+            let (size, addr) = sock.recv_from(&mut buf).unwrap();
+            let packet = PacketReader::new().deserialize(&Compression::decode(&buf[..size]).unwrap(), |_| None).unwrap();
+            if let Some(seq) = packet.header().seq() {
+                let mut ack = builder.gen_packet(PacketType::Heartbeat);
+                ack.header.set_acks(vec![(packet.header().channel(), Ack { next: seq + 1, bits: 0 })]);
+                let mut bytes = vec![0];
+                bytes.extend(builder.serialize_packet(&ack).unwrap());
+                sock.send_to(&bytes, addr).unwrap();
+            }
+            if packet.payload != PacketType::Heartbeat {
+                return (size, packet)
+            }
+        }
+    }
+
+    // Connect from a plain socket. Returns the request and reply size and the reply.
+    fn raw_connect(server: &mut Server, sock: &UdpSocket, hello: Hello) -> (usize, usize, Packet) {
+        let builder = PacketBuilder::new(Id::new("Bob".to_owned()).unwrap(), CodecKind::MessagePack);
+        let request = raw_send(server, sock, &builder, connect_packet(&hello));
+        let (reply, packet) = raw_recv(sock, &builder);
+        (request, reply, packet)
+    }
+
+    fn connect_packet(hello: &Hello) -> ClientPacket {
+        ClientPacket::Connect(PROTOCOL_VERSION, hello.encode().unwrap())
+    }
+
+    fn hello() -> Hello {
+        Hello {
+            capabilities: Capabilities::empty(), compression: vec![], key: None,
+            cookie: None, mailbox: None, padding: vec![0; CONNECT_PADDING]
+        }
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22092, 3)).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:33092").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        // Hello of a newer version that we couldn't decode
+        let builder = PacketBuilder::new(Id::new("Future".to_owned()).unwrap(), CodecKind::MessagePack);
+        let mut bytes = vec![0];
+        bytes.extend(builder.serialize(PacketType::Client(ClientPacket::Connect(PROTOCOL_VERSION + 1, vec![0xc1; CONNECT_PADDING]))).unwrap());
+        sock.send_to(&bytes, "127.0.0.1:22092").unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        server.poll().unwrap();
+
+        let mut buf = [0u8; 1024];
+        let size = sock.recv(&mut buf).unwrap();
+        let packet = PacketReader::new().deserialize(&Compression::decode(&buf[..size]).unwrap(), |_| None).unwrap();
+        assert_eq!(packet.payload, PacketType::Server(ServerPacket::ConnectRejected(
+            RejectReason::VersionMismatch(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION))));
+        assert!(server.adapter.shared_state.lock().unwrap().conns.is_empty());
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22093, 3)).unwrap();
+        let mut config = AdapterConfig::new(33093, 1);
+        config.capabilities = Capabilities::supported().without(Capabilities::COMPRESSION);
+        let mut client = Client::with_config(
+            Id::new("Some dude".to_owned()).unwrap(), config).unwrap();
+        client.connect("127.0.0.1:22093".parse().unwrap(), None).unwrap();
+        for _ in 0..100 {
+            server.poll().unwrap();
+            client.poll().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        for adapter in [&server.adapter, &client.adapter] {
+            let shared_state = adapter.shared_state.lock().unwrap();
+            let conn = shared_state.conns.values().next().unwrap();
+            assert!(conn.has(Capabilities::FRAGMENTATION));
+            assert!(!conn.has(Capabilities::COMPRESSION));
+            assert_eq!(conn.compression(), Compression::None);
+        }
+        server.shutdown().unwrap();
+        client.shutdown().unwrap();
+    }
+
+    #[test]
+    fn encrypted() {
+        let static_key = StaticKey::generate();
+        let mut config = AdapterConfig::new(22094, 3);
+        config.static_key = Some(static_key.clone());
+        let mut server = Server::setup(Id::new("Chef".to_owned()).unwrap(), config).unwrap();
+        let mut client = Client::new(Id::new("Some dude".to_owned()).unwrap(), 33094).unwrap();
+        client.connect("127.0.0.1:22094".parse().unwrap(), Some(static_key.public_key())).unwrap();
+        // Expects another server
+        let mut fooled = Client::new(Id::new("Fooled".to_owned()).unwrap(), 33095).unwrap();
+        fooled.connect("127.0.0.1:22094".parse().unwrap(),
+            Some(StaticKey::generate().public_key())).unwrap();
+        let mut sent = false;
+        for _ in 0..200 {
+            server.poll().unwrap();
+            client.poll().unwrap();
+            fooled.poll().unwrap();
+            if client.connected().is_some() && !sent {
+                client.message(TargetMode::Broadcast, "Secret".to_owned()).unwrap();
+                sent = true;
+            }
+            if !client.chat_log().is_empty() {
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.chat_log()[0].text, "Secret");
+        assert!(fooled.connected().is_none());
+        for adapter in [&server.adapter, &client.adapter] {
+            let shared_state = adapter.shared_state.lock().unwrap();
+            let conn = shared_state.conns.values().find(|conn| conn.addr().port() != 33095).unwrap();
+            assert!(conn.encrypted() && conn.has(Capabilities::ENCRYPTION));
+        }
+        server.shutdown().unwrap();
+        client.shutdown().unwrap();
+        fooled.shutdown().unwrap();
+    }
+
+    #[test]
+    fn cookie() {
+        let mut server = Server::setup(
+            Id::new("Longest ID".to_owned()).unwrap(), AdapterConfig::new(
+                22095, 3)).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:33096").unwrap();
+        let (request, reply, packet) = raw_connect(&mut server, &sock, hello());
+        let cookie = match packet.payload {
+            PacketType::Server(ServerPacket::Retry(cookie)) => cookie,
+            p => panic!("Expected retry, recv {:?}", p)
+        };
+        // No amplification
+        assert!(reply <= request);
+        assert!(server.adapter.shared_state.lock().unwrap().conns.is_empty());
+
+        let (_, _, packet) = raw_connect(&mut server, &sock, Hello {
+            cookie: Some([7; COOKIE_SIZE]), ..hello()
+        });
+        assert!(matches!(packet.payload, PacketType::Server(ServerPacket::Retry(_))));
+        assert!(server.adapter.shared_state.lock().unwrap().conns.is_empty());
+
+        let (_, _, packet) = raw_connect(&mut server, &sock, Hello {
+            cookie: Some(cookie), ..hello()
+        });
+        assert!(matches!(packet.payload, PacketType::Server(ServerPacket::ConnectAccepted(_))));
+        assert_eq!(server.adapter.shared_state.lock().unwrap().conns.len(), 1);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn spoofed() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22096, 3)).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:33097").unwrap();
+        let bob = Id::new("Bob".to_owned()).unwrap();
+        let builder = PacketBuilder::new(bob.clone(), CodecKind::MessagePack);
+        raw_send(&mut server, &sock, &builder, connect_packet(&hello()));
+        let cookie = match raw_recv(&sock, &builder).1.payload {
+            PacketType::Server(ServerPacket::Retry(cookie)) => cookie,
+            p => panic!("Expected retry, recv {:?}", p)
+        };
+        raw_send(&mut server, &sock, &builder, connect_packet(&Hello {
+            cookie: Some(cookie), ..hello()
+        }));
+
+        let alice = PacketBuilder::new(Id::new("Alice".to_owned()).unwrap(), CodecKind::MessagePack);
+        raw_send(&mut server, &sock, &alice, ClientPacket::Message(TargetMode::Broadcast, "Forged".to_owned(), None));
+        raw_send(&mut server, &sock, &builder, ClientPacket::Message(TargetMode::Broadcast, "Genuine".to_owned(), None));
+        loop {
+            if let PacketType::Server(ServerPacket::Message { source, text, .. }) = raw_recv(&sock, &builder).1.payload {
+                assert_eq!(source, bob);
+                assert_eq!(text, "Genuine");
+                break
+            }
+        }
+        let shared_state = server.adapter.shared_state.lock().unwrap();
+        assert_eq!(shared_state.conns.values().next().unwrap().strikes(), 1);
+        drop(shared_state);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn name_taken() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22097, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33098).unwrap();
+        alice.connect("127.0.0.1:22097".parse().unwrap(), None).unwrap();
+        for _ in 0..50 {
+            server.poll().unwrap();
+            alice.poll().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(alice.connected().is_some());
+        let mut impostor = Client::new(Id::new("Alice".to_owned()).unwrap(), 33099).unwrap();
+        impostor.connect("127.0.0.1:22097".parse().unwrap(), None).unwrap();
+        let mut rejected = None;
+        for _ in 0..200 {
+            server.poll().unwrap();
+            alice.poll().unwrap();
+            if let Err(e) = impostor.poll() {
+                rejected = Some(e);
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(rejected, Some(TellErr::Lib(LibErr::ConnectionRejected(RejectReason::NameTaken)))));
+        let shared_state = server.adapter.shared_state.lock().unwrap();
+        assert_eq!(shared_state.conn_ids.len(), 1);
+        assert_eq!(shared_state.addr_of(alice.id()), Some("127.0.0.1:33098".parse().unwrap()));
+        assert_eq!(shared_state.addr_of(impostor.id()), None);
+        drop(shared_state);
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        impostor.shutdown().unwrap();
+    }
+
+    #[test]
+    fn server_full() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22109, 1)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33128).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33129).unwrap();
+        alice.connect("127.0.0.1:22109".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice], connected);
+        bob.connect("127.0.0.1:22109".parse().unwrap(), None).unwrap();
+        let mut rejected = None;
+        for _ in 0..200 {
+            server.poll().unwrap();
+            alice.poll().unwrap();
+            if let Err(e) = bob.poll() {
+                rejected = Some(e);
+                break
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(rejected, Some(TellErr::Lib(LibErr::ConnectionRejected(RejectReason::ServerFull)))));
+        assert!(alice.connected().is_some());
+        assert_eq!(server.adapter.shared_state.lock().unwrap().conn_ids.len(), 1);
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
+
+    #[test]
+    fn whisper() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22098, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33100).unwrap();
+        alice.connect("127.0.0.1:22098".parse().unwrap(), None).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33101).unwrap();
+        bob.connect("127.0.0.1:22098".parse().unwrap(), None).unwrap();
+        let mut sent = false;
+        for _ in 0..200 {
+            server.poll().unwrap();
+            alice.poll().unwrap();
+            bob.poll().unwrap();
+            if !sent {
+                if let Some(target) = alice.peer("Bob").cloned() {
+                    alice.whisper(target, "Psst".to_owned()).unwrap();
+                    alice.whisper(Id::new("Ghost".to_owned()).unwrap(), "Anyone?".to_owned()).unwrap();
+                    sent = true;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(sent);
+        assert_eq!(bob.whisper_log().len(), 1);
+        assert_eq!(&bob.whisper_log()[0].source, alice.id());
+        assert_eq!(bob.whisper_log()[0].text, "Psst");
+        assert!(bob.chat_log().is_empty());
+        let states = alice.whisper_log().iter().map(|whisper| whisper.state).collect::<Vec<_>>();
+        assert_eq!(states, vec![Receipt::Delivered, Receipt::Failed]);
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
+
+    #[test]
+    fn offline() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22102, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33110).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33111).unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.connect("127.0.0.1:22102".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob], connected);
+        let bob_id = bob.id().clone();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[0].presence(&bob_id).is_some());
+        bob.disconnect().unwrap();
+        poll_until(&mut server, &mut [&mut alice], |c| c[0].presence(&bob_id).is_none());
+        alice.whisper(bob.id().clone(), "Psst".to_owned()).unwrap();
+        alice.whisper(bob.id().clone(), "Still there?".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice],
+            |c| c[0].whisper_log().iter().all(|whisper| whisper.state == Receipt::Queued));
+        let states = alice.whisper_log().iter().map(|whisper| whisper.state).collect::<Vec<_>>();
+        assert_eq!(states, vec![Receipt::Queued, Receipt::Queued]);
+        assert_eq!(server.mailboxes.held(bob.id()), 2);
+
+        // Same id, but not the one who got the mailbox token
+        let mut impostor = Client::new(bob_id.clone(), 33130).unwrap();
+        impostor.connect("127.0.0.1:22102".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut impostor], connected);
+        poll_all(&mut server, &mut [&mut alice, &mut impostor], 10);
+        assert!(impostor.whisper_log().is_empty());
+        assert_eq!(server.mailboxes.held(bob.id()), 2);
+        impostor.disconnect().unwrap();
+        poll_until(&mut server, &mut [&mut alice], |c| c[0].presence(&bob_id).is_none());
+        impostor.shutdown().unwrap();
+
+        bob.connect("127.0.0.1:22102".parse().unwrap(), None).unwrap();
+        // Receipts come in batches
+        poll_until(&mut server, &mut [&mut alice, &mut bob],
+            |c| c[0].whisper_log().iter().all(|whisper| whisper.state == Receipt::Delivered));
+        let texts = bob.whisper_log().iter().map(|whisper| whisper.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["Psst", "Still there?"]);
+        let states = alice.whisper_log().iter().map(|whisper| whisper.state).collect::<Vec<_>>();
+        assert_eq!(states, vec![Receipt::Delivered, Receipt::Delivered]);
+        assert_eq!(server.mailboxes.held(bob.id()), 0);
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
+
+    fn poll_all(server: &mut Server, clients: &mut [&mut Client], iterations: usize) {
+        for _ in 0..iterations {
+            server.poll().unwrap();
+            for client in clients.iter_mut() {
+                client.poll().unwrap();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Polls until the check passes, or gives up after a few secs
+    fn poll_until<F: Fn(&[&mut Client]) -> bool>(server: &mut Server, clients: &mut [&mut Client], done: F) {
+        for _ in 0..1000 {
+            poll_all(server, clients, 1);
+            if done(clients) {
+                return
+            }
+        }
+    }
+
+    fn connected(clients: &[&mut Client]) -> bool {
+        clients.iter().all(|client| client.connected().is_some())
+    }
+
+    #[test]
+    fn rooms() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22099, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33102).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33103).unwrap();
+        let mut carol = Client::new(Id::new("Carol".to_owned()).unwrap(), 33104).unwrap();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.connect("127.0.0.1:22099".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], connected);
+        alice.join("rust".to_owned()).unwrap();
+        alice.join("cats".to_owned()).unwrap();
+        bob.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| c[0].rooms().len() == 2 && c[1].room("rust").is_some());
+        alice.set_topic("rust".to_owned(), "Lifetimes".to_owned()).unwrap();
+        alice.message(TargetMode::Room("rust".to_owned()), "Hi".to_owned()).unwrap();
+        alice.leave("cats".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| c[0].rooms().len() == 1 && c[1].room("rust").is_some_and(|rust| rust.topic.is_some() && !rust.chat_log.is_empty()));
+        carol.list_rooms().unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| !c[2].room_list().is_empty());
+
+        let rust = bob.room("rust").unwrap();
+        assert_eq!(rust.topic.as_deref(), Some("Lifetimes"));
+        assert!(rust.peers.contains_key(alice.id()));
+        assert_eq!(rust.chat_log.iter().map(|entry| (&entry.source, entry.text.as_str())).collect::<Vec<_>>(),
+            vec![(alice.id(), "Hi")]);
+        assert!(bob.chat_log().is_empty());
+        assert_eq!(alice.rooms().keys().collect::<Vec<_>>(), vec!["rust"]);
+        assert!(carol.rooms().is_empty());
+        assert!(carol.chat_log().is_empty());
+        // Closed after alice left
+        assert_eq!(carol.room_list().len(), 1);
+        assert_eq!((carol.room_list()[0].name.as_str(), carol.room_list()[0].members), ("rust", 2));
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        carol.shutdown().unwrap();
+    }
+
+    #[test]
+    fn moderation() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22100, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33105).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33106).unwrap();
+        let mut eve = Client::new(Id::new("Eve".to_owned()).unwrap(), 33107).unwrap();
+        for client in [&mut alice, &mut bob, &mut eve] {
+            client.connect("127.0.0.1:22100".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve], connected);
+        alice.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve], |c| c[0].room("rust").is_some());
+        bob.join("rust".to_owned()).unwrap();
+        eve.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve],
+            |c| c[1].room("rust").is_some() && c[2].room("rust").is_some());
+        assert_eq!(alice.room("rust").unwrap().role, Role::Owner);
+        // Who may do what is up to the rooms, here it's only about getting the word out
+        alice.moderate("rust".to_owned(), ModAction::Mute(bob.id().clone(), 60)).unwrap();
+        alice.moderate("rust".to_owned(), ModAction::Kick(eve.id().clone())).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve],
+            |c| c[2].rooms().is_empty() && c[1].room("rust").is_some_and(|rust| !rust.peers.contains_key(c[2].id())));
+        bob.message(TargetMode::Room("rust".to_owned()), "Can you hear me?".to_owned()).unwrap();
+        poll_all(&mut server, &mut [&mut alice, &mut bob, &mut eve], 10);
+
+        assert!(eve.rooms().is_empty());
+        assert!(alice.room("rust").is_some());
+        let rust = bob.room("rust").unwrap();
+        assert!(!rust.peers.contains_key(eve.id()));
+        assert!(rust.chat_log.is_empty());
+        assert!(alice.room("rust").unwrap().chat_log.is_empty());
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        eve.shutdown().unwrap();
+    }
+
+    #[test]
+    fn history() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22101, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33108).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33109).unwrap();
+        alice.connect("127.0.0.1:22101".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice], connected);
+        alice.join("rust".to_owned()).unwrap();
+        for text in ["One", "Two", "Three"] {
+            alice.message(TargetMode::Broadcast, text.to_owned()).unwrap();
+        }
+        alice.message(TargetMode::Room("rust".to_owned()), "Lifetimes".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice],
+            |c| c[0].chat_log().len() == 3 && c[0].room("rust").is_some_and(|rust| !rust.chat_log.is_empty()));
+        // Newcomer catches up
+        bob.connect("127.0.0.1:22101".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[1].chat_log().len() == 3);
+        bob.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[1].room("rust").is_some_and(|rust| !rust.chat_log.is_empty()));
+
+        let texts = |log: &Vec<ChatEntry>| log.iter().map(|entry| entry.text.clone()).collect::<Vec<_>>();
+        assert_eq!(texts(bob.chat_log()), vec!["One", "Two", "Three"]);
+        assert_eq!(&bob.chat_log()[0].source, alice.id());
+        assert_eq!(texts(&bob.room("rust").unwrap().chat_log), vec!["Lifetimes"]);
+        // Everything fit into the backlog
+        assert!(!bob.more_history(Scope::Global).unwrap());
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
+
+    #[test]
+    fn amend() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22103, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33112).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33113).unwrap();
+        let mut eve = Client::new(Id::new("Eve".to_owned()).unwrap(), 33114).unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.connect("127.0.0.1:22103".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob], connected);
+        alice.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[0].room("rust").is_some());
+        bob.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[1].room("rust").is_some());
+        alice.message(TargetMode::Broadcast, "Helo".to_owned()).unwrap();
+        bob.message(TargetMode::Room("rust".to_owned()), "Spam".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(
+            |client| !client.chat_log().is_empty() && !client.room("rust").unwrap().chat_log.is_empty()));
+        let greeting = bob.chat_log()[0].id;
+        let spam = alice.room("rust").unwrap().chat_log[0].id;
+        assert_ne!(spam, greeting);
+
+        alice.edit(greeting, "Hello".to_owned()).unwrap();
+        // Neither author nor moderator
+        bob.delete(greeting).unwrap();
+        bob.edit(spam, "Eggs".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(
+            |client| client.chat_log()[0].text == "Hello" && client.room("rust").unwrap().chat_log[0].text == "Eggs"));
+        // Owner moderates it
+        alice.edit(spam, "Ham".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(
+            |client| client.room("rust").unwrap().chat_log[0].text == "Ham"));
+        alice.delete(spam).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(
+            |client| client.room("rust").unwrap().chat_log[0].state == MessageState::Deleted));
+        for client in [&alice, &bob] {
+            let entry = &client.chat_log()[0];
+            assert_eq!((entry.text.as_str(), entry.state), ("Hello", MessageState::Edited));
+            let entry = &client.room("rust").unwrap().chat_log[0];
+            assert_eq!((entry.text.as_str(), entry.state), ("", MessageState::Deleted));
+        }
+        // Newcomers see the history as it is now
+        eve.connect("127.0.0.1:22103".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve], |c| !c[2].chat_log().is_empty());
+        assert_eq!(eve.chat_log().iter().map(|entry| (entry.id, entry.text.as_str())).collect::<Vec<_>>(),
+            vec![(greeting, "Hello")]);
+        // Server's moderators do so in the open
+        server.add_moderator(bob.id().clone());
+        bob.edit(greeting, "Hello all".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut eve],
+            |c| c.iter().all(|client| client.chat_log()[0].text == "Hello all"));
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        eve.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reactions() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22104, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33115).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33116).unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.connect("127.0.0.1:22104".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob], connected);
+        alice.message(TargetMode::Broadcast, "Ship it?".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(|client| !client.chat_log().is_empty()));
+        let message = bob.chat_log()[0].id;
+        alice.react(message, "👍".to_owned()).unwrap();
+        bob.react(message, "👍".to_owned()).unwrap();
+        bob.react(message, "🎉".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(|client| {
+            let reactions = &client.chat_log()[0].reactions;
+            reactions.len() == 2 && reactions.iter().map(|reaction| reaction.count()).sum::<usize>() == 3
+        }));
+        bob.unreact(message, "🎉".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c.iter().all(|client| client.chat_log()[0].reactions.len() == 1));
+        for client in [&alice, &bob] {
+            let reactions = &client.chat_log()[0].reactions;
+            assert_eq!(reactions.len(), 1);
+            assert_eq!((reactions[0].emoji.as_str(), reactions[0].count()), ("👍", 2));
+            assert!(reactions[0].ids.contains(alice.id()) && reactions[0].ids.contains(bob.id()));
+        }
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
+
+    #[test]
+    fn threads() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22105, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33117).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33118).unwrap();
+        let mut carol = Client::new(Id::new("Carol".to_owned()).unwrap(), 33119).unwrap();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.connect("127.0.0.1:22105".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], connected);
+        alice.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[0].room("rust").is_some());
+        bob.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[1].room("rust").is_some());
+        alice.message(TargetMode::Room("rust".to_owned()), "Async or threads?".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| c[..2].iter().all(|client| !client.room("rust").unwrap().chat_log.is_empty()));
+        let root = bob.room("rust").unwrap().chat_log[0].id;
+        let room = TargetMode::Room("rust".to_owned());
+        bob.reply(room.clone(), root, "Async".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[0].thread(root).is_some());
+        let first = alice.thread(root).unwrap()[0].id;
+        // Joins the root's thread, in the open or elsewhere it's turned down
+        alice.reply(room.clone(), first, "Why?".to_owned()).unwrap();
+        alice.reply(TargetMode::Broadcast, root, "Leak".to_owned()).unwrap();
+        carol.reply(room.clone(), root, "Lurking".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| c[..2].iter().all(|client| client.thread(root).is_some_and(|thread| thread.len() == 2)));
+        // Give the turned down ones a chance to slip through
+        poll_all(&mut server, &mut [&mut alice, &mut bob, &mut carol], 10);
+
+        let texts = |log: &Vec<ChatEntry>| log.iter().map(|entry| entry.text.clone()).collect::<Vec<_>>();
+        for client in [&alice, &bob] {
+            let view = client.room("rust").unwrap();
+            assert_eq!(texts(&view.chat_log), vec!["Async or threads?"]);
+            assert_eq!(view.chat_log[0].replies, 2);
+            assert_eq!(texts(client.thread(root).unwrap()), vec!["Async", "Why?"]);
+        }
+        assert!(carol.thread(root).is_none() && carol.chat_log().is_empty());
+
+        // Latecomers fetch it
+        carol.join("rust".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| c[2].room("rust").is_some_and(|rust| !rust.chat_log.is_empty()));
+        assert_eq!(carol.room("rust").unwrap().chat_log[0].replies, 2);
+        carol.request_thread(first).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[2].thread(root).is_some());
+        assert_eq!(texts(carol.thread(root).unwrap()), vec!["Async", "Why?"]);
+        assert_eq!(carol.thread(root).unwrap()[1].parent, Some(root));
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        carol.shutdown().unwrap();
+    }
+
+    #[test]
+    fn presence() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22106, 3)).unwrap();
+        server.presences = Presences::new(1.);
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33120).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33121).unwrap();
+        let mut carol = Client::new(Id::new("Carol".to_owned()).unwrap(), 33122).unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.connect("127.0.0.1:22106".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob], connected);
+        let (alice_id, bob_id) = (alice.id().clone(), bob.id().clone());
+        let coding = Presence {
+            status: Status::Busy, text: Some("Coding".to_owned())
+        };
+        bob.set_presence(coding.status, coding.text.clone()).unwrap();
+        alice.typing(TargetMode::Broadcast).unwrap();
+        alice.typing(TargetMode::Broadcast).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob],
+            |c| !c[1].typing_peers().is_empty() && c[0].presence(&bob_id) == Some(&coding));
+        assert_eq!(bob.typing_peers(), vec![(alice.id(), &TargetMode::Broadcast)]);
+        assert!(alice.typing_peers().is_empty());
+        assert_eq!(alice.presence(bob.id()), Some(&coding));
+
+        // Nothing but heartbeats for a while
+        poll_until(&mut server, &mut [&mut alice, &mut bob],
+            |c| c[1].presence(&alice_id).is_some_and(|presence| presence.status == Status::Away));
+        assert_eq!(bob.presence(alice.id()).unwrap().status, Status::Away);
+        assert_eq!(alice.presence(bob.id()), Some(&coding));
+        alice.message(TargetMode::Broadcast, "Back".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[1].presence(&alice_id) == Some(&Presence::default()));
+        assert_eq!(bob.presence(alice.id()), Some(&Presence::default()));
+        assert!(bob.typing_peers().is_empty());
+
+        carol.connect("127.0.0.1:22106".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[2].presence(&bob_id) == Some(&coding));
+        assert_eq!(carol.presence(bob.id()), Some(&coding));
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        carol.shutdown().unwrap();
+    }
+
+    #[test]
+    fn receipts() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22107, 3)).unwrap();
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33123).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33124).unwrap();
+        let mut carol = Client::new(Id::new("Carol".to_owned()).unwrap(), 33125).unwrap();
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.connect("127.0.0.1:22107".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], connected);
+        let (bob_id, carol_id) = (bob.id().clone(), carol.id().clone());
+        let ghost = Id::new("Ghost".to_owned()).unwrap();
+        alice.message(TargetMode::Multicast(vec![bob_id.clone(), carol_id.clone(), ghost.clone()]), "Standup?".to_owned()).unwrap();
+        alice.message(TargetMode::Broadcast, "Not tracked".to_owned()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol],
+            |c| [&bob_id, &carol_id].iter().all(|id| c[0].sent()[0].receipts.get(*id) == Some(&Receipt::Delivered)));
+        assert_eq!(alice.sent().len(), 1);
+        let message = alice.sent()[0].id.unwrap();
+        assert_eq!(bob.unread(), &vec![message]);
+        assert_eq!(alice.sent()[0].receipts[bob.id()], Receipt::Delivered);
+        assert_eq!(alice.sent()[0].receipts[&ghost], Receipt::Failed);
+        assert_eq!(alice.sent()[0].state(), Receipt::Failed);
+
+        bob.read_all();
+        bob.read(message);
+        poll_until(&mut server, &mut [&mut alice, &mut bob, &mut carol], |c| c[0].sent()[0].receipts[&bob_id] == Receipt::Read);
+        assert!(bob.unread().is_empty());
+        assert_eq!(alice.sent()[0].receipts[bob.id()], Receipt::Read);
+        assert_eq!(alice.sent()[0].receipts[carol.id()], Receipt::Delivered);
+        assert_eq!(carol.unread(), &vec![message]);
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+        carol.shutdown().unwrap();
+    }
+
+    #[test]
+    fn transfer() {
+        let mut server = Server::setup(
+            Id::new("Chef".to_owned()).unwrap(), AdapterConfig::new(
+                22108, 3)).unwrap();
+        // Takes a few secs, so there's time to go away in between
+        server.limit_files(FILE_CHUNK_SIZE as u64 * 200, FILE_CHUNK_SIZE as u64 * 50);
+        let mut alice = Client::new(Id::new("Alice".to_owned()).unwrap(), 33126).unwrap();
+        let mut bob = Client::new(Id::new("Bob".to_owned()).unwrap(), 33127).unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.connect("127.0.0.1:22108".parse().unwrap(), None).unwrap();
+        }
+        poll_until(&mut server, &mut [&mut alice, &mut bob], connected);
+        let bob_id = bob.id().clone();
+        let data = (0..FILE_CHUNK_SIZE * 150 + 13).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        alice.offer_file(bob.id().clone(), "big.log".to_owned(), vec![0; FILE_CHUNK_SIZE * 200 + 1]).unwrap();
+        alice.offer_file(bob.id().clone(), "tell.log".to_owned(), data.clone()).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[0].transfers()[1].id.is_some() && !c[1].transfers().is_empty());
+        assert_eq!(alice.transfer_events(), vec![TransferEvent::Rejected("big.log".to_owned(), RequestError::FileTooLarge(FILE_CHUNK_SIZE as u64 * 200))]);
+        let id = match bob.transfer_events()[..] {
+            [TransferEvent::Offered(id)] => id,
+            ref events => panic!("Expected an offer, got {:?}", events)
+        };
+        assert_eq!(alice.transfers()[1].id, Some(id));
+        bob.accept_file(id).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[1].transfer(id).unwrap().progress() > 0);
+        bob.disconnect().unwrap();
+        poll_until(&mut server, &mut [&mut alice], |c| c[0].presence(&bob_id).is_none());
+        let got = bob.transfer(id).unwrap().progress();
+        assert!(got > 0 && got < data.len() as u64);
+
+        // Picks up where it stopped
+        bob.connect("127.0.0.1:22108".parse().unwrap(), None).unwrap();
+        poll_until(&mut server, &mut [&mut alice, &mut bob], |c| c[0].transfer(id).unwrap().state == TransferState::Completed);
+        let transfer = bob.transfer(id).unwrap();
+        assert_eq!(transfer.state, TransferState::Completed);
+        assert_eq!(transfer.data, data);
+        assert_eq!(alice.transfer(id).unwrap().state, TransferState::Completed);
+        let events = alice.transfer_events();
+        assert!(events.iter().any(|event| matches!(event, TransferEvent::Progress(..))));
+        assert_eq!(events.last(), Some(&TransferEvent::Completed(id)));
+        assert_eq!(bob.transfer_events().last(), Some(&TransferEvent::Completed(id)));
+        assert!(server.relay.resumable(alice.id()).is_empty());
+        server.shutdown().unwrap();
+        alice.shutdown().unwrap();
+        bob.shutdown().unwrap();
+    }
 }
@@ -1,6 +1,10 @@
-use std::{sync::{atomic::{AtomicBool, Ordering}, Mutex}, net::{UdpSocket, SocketAddr}, collections::HashMap};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Mutex}, net::{UdpSocket, SocketAddr}, collections::HashMap, time::{Instant, Duration}};
 use crate::{err::{TResult, TellErr, LibErr}, util::Metrics, id::Id};
-use super::{conn::{UdpConnection, Connection}, adapter::AdapterConfig};
+use super::{conn::{UdpConnection, Connection}, adapter::{AdapterConfig, UDP_MAX_STRIKES, UDP_IGNORE_DURATION}};
+
+// Bounds the strike bookkeeping of unconnected sources (spoofed addrs are free)
+const MAX_STRANGER_STRIKES: usize = 1024;
+const MAX_IGNORED: usize = 1024;
 
 pub trait SharedState {
     fn running(&self) -> bool;
@@ -13,17 +17,45 @@ pub struct UdpSharedState {
     pub sock: UdpSocket,
     running: AtomicBool,
     pub conns: HashMap<SocketAddr, UdpConnection>,
+    // Addrs of all connections that know their remote id
     pub conn_ids: HashMap<Id, SocketAddr>,
-    config: AdapterConfig
+    config: AdapterConfig,
+    fragment_id: u32,
+    session: u32,
+    // Packet timestamps are relative to this
+    epoch: Instant,
+    // Strikes of sources without a connection
+    strikes: HashMap<SocketAddr, u32>,
+    ignored: HashMap<SocketAddr, Instant>
 }
 
 impl UdpSharedState {
     pub fn new(sock: UdpSocket, running: AtomicBool, config: AdapterConfig) -> Self {
         Self {
-            sock, running, conns: HashMap::new(), conn_ids: HashMap::new(), config
+            sock, running, conns: HashMap::new(), conn_ids: HashMap::new(), config,
+            fragment_id: 0, session: 0, epoch: Instant::now(), strikes: HashMap::new(), ignored: HashMap::new()
         }
     }
 
+    pub fn config(&self) -> &AdapterConfig {
+        &self.config
+    }
+
+    pub fn next_fragment_id(&mut self) -> u32 {
+        self.fragment_id = self.fragment_id.wrapping_add(1);
+        self.fragment_id
+    }
+
+    pub fn next_session(&mut self) -> u32 {
+        self.session = self.session.wrapping_add(1);
+        self.session
+    }
+
+    // Microsecs since the adapter started
+    pub fn clock(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
     pub fn running(&self) -> bool {
         self.running.load(Ordering::Relaxed)
     }
@@ -38,16 +70,125 @@ impl UdpSharedState {
         } else if self.conns.len() >= self.config.max_conns as usize {
             Err(TellErr::Lib(LibErr::MaxConnectionsReached(self.conns.len())))
         } else {
+            if let Some(id) = conn.id() {
+                self.conn_ids.insert(id.clone(), conn.addr());
+            }
             self.conns.insert(conn.addr(), conn);
             Ok(())
         }
     }
 
+    // Outgoing connection learned the remote id
+    pub fn establish(&mut self, addr: SocketAddr, id: Id) -> TResult {
+        match self.conns.get_mut(&addr) {
+            Some(conn) => {
+                self.conn_ids.insert(id.clone(), addr);
+                conn.connect(id)
+            },
+            None => Err(TellErr::Lib(LibErr::PeerNotConnected(addr)))
+        }
+    }
+
     pub fn remove_conn(&mut self, addr: SocketAddr) -> Option<UdpConnection> {
-        self.conns.remove(&addr)
+        let conn = self.conns.remove(&addr)?;
+        if let Some(id) = conn.id() {
+            self.conn_ids.remove(id);
+        }
+        Some(conn)
+    }
+
+    pub fn addr_of(&self, id: &Id) -> Option<SocketAddr> {
+        self.conn_ids.get(id).copied()
+    }
+
+    // Names are shown without their sign, so they must be unique among connections
+    pub fn name_taken(&self, name: &str) -> bool {
+        self.conn_ids.keys().any(|id| id.name() == name)
+    }
+
+    // Count a protocol violation of `addr` and start ignoring it once it collected
+    // too many. Returns the strikes so far.
+    pub fn strike(&mut self, addr: SocketAddr) -> u32 {
+        let strikes = if let Some(conn) = self.conns.get_mut(&addr) {
+            conn.strike()
+        } else {
+            if self.strikes.len() >= MAX_STRANGER_STRIKES {
+                self.strikes.clear();
+            }
+            let strikes = self.strikes.entry(addr).or_insert(0);
+            *strikes += 1;
+            *strikes
+        };
+        if strikes >= UDP_MAX_STRIKES {
+            self.ignore(addr, UDP_IGNORE_DURATION);
+        }
+        strikes
+    }
+
+    // Drop all datagrams from `addr` for the given amount of secs
+    pub fn ignore(&mut self, addr: SocketAddr, secs: f32) {
+        self.strikes.remove(&addr);
+        if self.ignored.len() >= MAX_IGNORED && !self.ignored.contains_key(&addr) {
+            self.expire_ignored();
+            // Still full: Give up on the addr that would be forgiven next
+            if self.ignored.len() >= MAX_IGNORED {
+                if let Some(next) = self.ignored.iter().min_by_key(|(_, &until)| until).map(|(&addr, _)| addr) {
+                    self.ignored.remove(&next);
+                }
+            }
+        }
+        self.ignored.insert(addr, Instant::now() + Duration::from_secs_f32(secs));
+    }
+
+    pub fn ignored(&mut self, addr: SocketAddr) -> bool {
+        match self.ignored.get(&addr) {
+            Some(&until) if Instant::now() < until => true,
+            Some(_) => {
+                self.ignored.remove(&addr);
+                false
+            },
+            None => false
+        }
+    }
+
+    pub fn expire_ignored(&mut self) {
+        let now = Instant::now();
+        self.ignored.retain(|_, &mut until| now < until);
     }
 
     pub fn conn_addrs(&self) -> Vec<SocketAddr> {
         self.conns.keys().map(|&addr| addr).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{UdpSocket, SocketAddr}, sync::atomic::AtomicBool};
+    use crate::net::adapter::AdapterConfig;
+    use super::{UdpSharedState, MAX_IGNORED};
+
+    #[test]
+    fn ignored() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut state = UdpSharedState::new(sock, AtomicBool::new(true), AdapterConfig::new(0, 1));
+        let addr = |port: u16| SocketAddr::from(([10, 0, 0, 1], port));
+        state.ignore(addr(0), 0.);
+        for port in 1..MAX_IGNORED as u16 {
+            state.ignore(addr(port), 60.);
+        }
+        assert!(state.ignored(addr(1)));
+        // Full, the expired entry goes first
+        state.ignore(addr(MAX_IGNORED as u16), 30.);
+        assert_eq!(state.ignored.len(), MAX_IGNORED);
+        assert!(!state.ignored.contains_key(&addr(0)));
+        // Then the one that expires soonest
+        state.ignore(addr(MAX_IGNORED as u16 + 1), 60.);
+        assert_eq!(state.ignored.len(), MAX_IGNORED);
+        assert!(!state.ignored(addr(MAX_IGNORED as u16)));
+        assert!(state.ignored(addr(MAX_IGNORED as u16 + 1)));
+
+        state.ignore(addr(0), 0.);
+        state.expire_ignored();
+        assert!(!state.ignored.contains_key(&addr(0)));
+    }
+}
@@ -1,11 +1,26 @@
+use rmp_serde::{Serializer, Deserializer};
 use serde::{Serialize, Deserialize};
-use crate::{id::Id, header::PacketHeader};
+use crate::{err::TResult, id::Id, header::PacketHeader, codec::CodecKind, net::{fragment::Fragment, compress::Compression, capability::Capabilities, secure::PublicKey, cookie::Cookie}, room::{Role, ModAction}, history::{Scope, Record, Reaction}, presence::Presence, receipt::{Receipt, ReceiptUpdate}, mailbox::MailboxToken, transfer::{FileInfo, Chunk}};
+
+// Unverified connects are answered with a cookie. The padding makes sure that reply is
+// smaller than the request, so spoofed connects can't be used for amplification.
+pub const CONNECT_PADDING: usize = 128;
+
+// Version of the packet types below. Servers accept every version in between.
+// 2 limited pending file offers, 3 turns connects down when the server is full,
+// 4 added the mailbox token and 5 sizes file chunks by codec.
+pub const PROTOCOL_VERSION: u16 = 5;
+pub const MIN_PROTOCOL_VERSION: u16 = 5;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PacketType {
     Client(ClientPacket),
     Server(ServerPacket),
-    Heartbeat
+    Heartbeat,
+    // Echoes the timestamp of a heartbeat to measure the round trip
+    HeartbeatReply(u64),
+    // Part of a packet that didn't fit into a single datagram
+    Fragment(Fragment)
 }
 
 impl PacketType {
@@ -24,11 +39,13 @@ impl PacketType {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum TargetMode {
     Broadcast,
     Multicast(Vec<Id>),
-    Unicast(Id)
+    Unicast(Id),
+    // Members of the room, the sender must be one of them
+    Room(String)
 }
 
 // #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -36,34 +53,263 @@ pub enum TargetMode {
 //     Peers
 // }
 
+// Always MessagePack, and only decoded once the version is known to be supported
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub capabilities: Capabilities,
+    // Supported compression algorithms, by preference
+    pub compression: Vec<Compression>,
+    // Ephemeral key, if the client wants an encrypted session
+    pub key: Option<PublicKey>,
+    // Echoed from the server's retry
+    pub cookie: Option<Cookie>,
+    // Handed out on an earlier connect with the same id, claims what was held meanwhile
+    pub mailbox: Option<MailboxToken>,
+    #[serde(with = "serde_bytes")]
+    pub padding: Vec<u8>
+}
+
+impl Hello {
+    pub fn encode(&self) -> TResult<Vec<u8>> {
+        let mut buf = vec![];
+        self.serialize(&mut Serializer::new(&mut buf))?;
+        Ok(buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> TResult<Hello> {
+        Ok(Hello::deserialize(&mut Deserializer::new(bytes))?)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ClientPacket {
-    Connect,
+    // Protocol version and the encoded hello
+    Connect(u16, #[serde(with = "serde_bytes")] Vec<u8>),
     Disconnect,
-    Message(TargetMode, String),
-    RequestPeers
+    // Optionally replies to a stored message, sent to the same target
+    Message(TargetMode, String, Option<u64>),
+    RequestPeers,
+    Join(String),
+    Leave(String),
+    ListRooms,
+    // Empty topic clears it
+    SetTopic(String, String),
+    Moderate(String, ModAction),
+    // Page of records older than `before`, or the latest ones
+    RequestHistory {
+        scope: Scope,
+        before: Option<u64>
+    },
+    // Only the author, or an operator of the room, may edit or delete a message
+    Edit(u64, String),
+    Delete(u64),
+    // Emoji on a stored message
+    React(u64, String),
+    Unreact(u64, String),
+    // All replies to a message
+    RequestThread(u64),
+    // Sent unreliably while writing, at most every `TYPING_INTERVAL`
+    Typing(TargetMode),
+    SetPresence(Presence),
+    // Unicast and multicast messages we got or read since the last batch
+    Receipts {
+        delivered: Vec<u64>,
+        read: Vec<u64>
+    },
+    // File for a connected peer
+    Offer(Id, FileInfo),
+    // Chunks from the index on, also to resume or get a broken one again
+    Accept(u64, u32),
+    // Sent on `CHANNEL_BULK`, at most `FILE_WINDOW` chunks ahead of the last ack
+    Chunk(Chunk),
+    // Chunks got so far, by the receiver
+    ChunkAck(u64, u32),
+    // By either side, also to decline an offer
+    CancelTransfer(u64)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum DisconnectReason {
     Manual,
-    Timeout
+    Timeout,
+    ProtocolViolation,
+    // Removed from a room by a moderator
+    Kicked
+}
+
+// Why a connect was turned down. The peer never becomes connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectReason {
+    // Server only speaks the given codec
+    CodecMismatch(CodecKind),
+    // Server only speaks the given range of protocol versions
+    VersionMismatch(u16, u16),
+    // Another peer is connected under the same name
+    NameTaken,
+    // No room for another connection
+    ServerFull
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handshake {
+    // Replaces the full id in packet headers of this connection
+    pub session: u32,
+    pub version: u16,
+    // Subset of the offered capabilities that is active for this connection
+    pub capabilities: Capabilities,
+    pub compression: Compression,
+    // Only if the id's mailbox is the peer's own
+    pub mailbox: Option<MailboxToken>
+}
+
+// Why the server couldn't carry out a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RequestError {
+    // Target isn't connected and never was, or isn't connected to take a file
+    PeerOffline(Id),
+    // Target is offline and has too many messages waiting
+    MailboxFull(Id),
+    // Rooms weren't negotiated for this connection
+    RoomsUnsupported,
+    InvalidRoomName(String),
+    NotInRoom(String),
+    // Max length
+    TopicTooLong(usize),
+    // Not the author nor a moderator, role too low for this action, or the target's too high
+    NotPermitted,
+    PeerNotInRoom(Id),
+    // Secs left
+    Banned(String, u32),
+    Muted(String, u32),
+    TopicLocked(String),
+    // Not in the history, or deleted already. Whispers can't be changed.
+    UnknownMessage(u64),
+    InvalidReaction(String),
+    // Max different reactions per message
+    TooManyReactions(usize),
+    // Replies must go where their parent went
+    ThreadMismatch(u64),
+    // Max length
+    StatusTooLong(usize),
+    // Max bytes
+    FileTooLarge(u64),
+    // Empty or too long name, or no sha256
+    InvalidFile,
+    // Not relayed (anymore), or not ours
+    UnknownTransfer(u64),
+    // Max offers waiting to be accepted
+    TooManyOffers(usize)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub topic: Option<String>,
+    pub members: u32
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ServerPacket {
+    // Sent to the connecting peer only, carries what was agreed on
+    ConnectAccepted(Handshake),
+    // Sent to everyone else
     PeerConnected(Id),
+    ConnectRejected(RejectReason),
+    // Connect again with this cookie
+    Retry(Cookie),
     PeerDisconnected(Id, DisconnectReason),
     PeerTimedOut(Id),
     Message {
+        id: u64,
         source: Id,
         target_mode: TargetMode,
+        text: String,
+        // Root of the thread it replies to
+        parent: Option<u64>
+    },
+    // Sent to everyone who got the message
+    Edited {
+        scope: Scope,
+        id: u64,
         text: String
     },
-    RequestReply(Vec<Id>)
+    Deleted {
+        scope: Scope,
+        id: u64,
+        // Author or moderator
+        source: Id
+    },
+    // Replies to the root, oldest first. Split into several packets for long threads.
+    Thread {
+        scope: Scope,
+        root: u64,
+        replies: Vec<Record>
+    },
+    // All reactions of the message after one changed
+    Reactions {
+        scope: Scope,
+        id: u64,
+        reactions: Vec<Reaction>
+    },
+    // Id of our unicast or multicast message and where it stands for each recipient
+    Sent {
+        id: u64,
+        receipts: Vec<(Id, Receipt)>
+    },
+    // Receipts of our messages since the last batch
+    Receipts(Vec<ReceiptUpdate>),
+    // Sent to the target and, with the id, back to the source
+    Offer {
+        id: u64,
+        source: Id,
+        target: Id,
+        file: FileInfo
+    },
+    // To the source, send the chunks from the index on. Also after it reconnected.
+    Accepted(u64, u32),
+    Chunk(Chunk),
+    ChunkAck(u64, u32),
+    // Sent to the other side
+    TransferCancelled(u64, Id),
+    Error(RequestError),
+    // Connected peers
+    RequestReply(Vec<(Id, Presence)>),
+    // Sent to everyone once it changed, also when the peer went idle
+    Presence(Id, Presence),
+    // Sent unreliably to whom the peer is writing
+    Typing(Id, TargetMode),
+    // Sent to the joining peer, includes all members
+    Joined {
+        room: String,
+        topic: Option<String>,
+        topic_locked: bool,
+        members: Vec<(Id, Role)>
+    },
+    // Sent to the other members
+    PeerJoined(String, Id),
+    // Sent to the remaining members and the leaving peer
+    PeerLeft(String, Id, DisconnectReason),
+    // Sent to all members, including the target
+    Moderation {
+        room: String,
+        source: Id,
+        action: ModAction
+    },
+    // Oldest first. `more` if there are older records still.
+    History {
+        scope: Scope,
+        records: Vec<Record>,
+        more: bool
+    },
+    RoomList(Vec<RoomInfo>),
+    Topic {
+        room: String,
+        source: Id,
+        topic: Option<String>
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Packet {
     pub header: PacketHeader,
     pub payload: PacketType
@@ -0,0 +1,145 @@
+use std::{collections::{HashMap, hash_map::Entry}, time::{Instant, Duration}};
+use serde::{Serialize, Deserialize};
+use crate::{id::Id, packet::RequestError};
+
+// Secs an online peer may send nothing but heartbeats until it shows as away
+pub const AWAY_AFTER: f32 = 5. * 60.;
+// Secs between checks for idle peers
+pub const IDLE_CHECK_INTERVAL: f32 = 1.;
+pub const MAX_STATUS_TEXT_LEN: usize = 64;
+// Min secs between typing notices of a peer, whatever the target
+pub const TYPING_INTERVAL: f32 = 2.;
+// Secs a typing notice is shown for, unless renewed
+pub const TYPING_TIMEOUT: f32 = TYPING_INTERVAL * 2.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Online,
+    Away,
+    Busy
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub status: Status,
+    pub text: Option<String>
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            status: Status::Online, text: None
+        }
+    }
+}
+
+// Presence of all connected peers and who may send typing notices when
+pub struct Presences {
+    // What peers chose themselves
+    chosen: HashMap<Id, Presence>,
+    // Last one announced, may be away while idle
+    shown: HashMap<Id, Presence>,
+    // Last typing notice relayed of each peer
+    typing: HashMap<Id, Instant>,
+    // Secs
+    away_after: f32
+}
+
+impl Presences {
+    pub fn new(away_after: f32) -> Self {
+        Self {
+            chosen: HashMap::new(), shown: HashMap::new(), typing: HashMap::new(), away_after
+        }
+    }
+
+    pub fn choose(&mut self, id: Id, presence: Presence) -> Result<(), RequestError> {
+        if presence.text.as_ref().is_some_and(|text| text.len() > MAX_STATUS_TEXT_LEN) {
+            return Err(RequestError::StatusTooLong(MAX_STATUS_TEXT_LEN))
+        }
+        self.chosen.insert(id, presence);
+        Ok(())
+    }
+
+    pub fn away_after(&self) -> f32 {
+        self.away_after
+    }
+
+    pub fn get(&self, id: &Id) -> Presence {
+        self.shown.get(id).cloned().unwrap_or_default()
+    }
+
+    // Presence after the peer was idle for the given secs. Returns it if it has to be announced.
+    pub fn refresh(&mut self, id: &Id, idle: f32) -> Option<Presence> {
+        let mut presence = self.chosen.get(id).cloned().unwrap_or_default();
+        if presence.status == Status::Online && idle >= self.away_after {
+            presence.status = Status::Away;
+        }
+        if self.get(id) == presence {
+            None
+        } else {
+            self.shown.insert(id.clone(), presence.clone());
+            Some(presence)
+        }
+    }
+
+    pub fn remove(&mut self, id: &Id) {
+        self.chosen.remove(id);
+        self.shown.remove(id);
+        self.typing.remove(id);
+    }
+
+    // Is the notice due, or was one of the peer relayed just now? Varying the target doesn't
+    // get more of them through.
+    pub fn typing(&mut self, id: &Id) -> bool {
+        let interval = Duration::from_secs_f32(TYPING_INTERVAL);
+        self.typing.retain(|_, since| since.elapsed() < interval);
+        match self.typing.entry(id.clone()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, packet::RequestError};
+    use super::{Presences, Presence, Status, AWAY_AFTER, MAX_STATUS_TEXT_LEN};
+
+    #[test]
+    fn refresh() {
+        let mut presences = Presences::new(AWAY_AFTER);
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        assert_eq!(presences.refresh(&alice, 0.), None);
+        assert_eq!(presences.refresh(&alice, AWAY_AFTER).map(|presence| presence.status), Some(Status::Away));
+        assert_eq!(presences.refresh(&alice, AWAY_AFTER + 1.), None);
+        assert_eq!(presences.refresh(&alice, 0.), Some(Presence::default()));
+
+        // Busy stays busy while idle
+        let busy = Presence {
+            status: Status::Busy, text: Some("In a meeting".to_owned())
+        };
+        presences.choose(alice.clone(), busy.clone()).unwrap();
+        assert_eq!(presences.refresh(&alice, AWAY_AFTER), Some(busy.clone()));
+        assert_eq!(presences.get(&alice), busy);
+        assert_eq!(presences.choose(alice.clone(), Presence {
+            status: Status::Busy, text: Some("x".repeat(MAX_STATUS_TEXT_LEN + 1))
+        }), Err(RequestError::StatusTooLong(MAX_STATUS_TEXT_LEN)));
+
+        presences.remove(&alice);
+        assert_eq!(presences.get(&alice), Presence::default());
+    }
+
+    #[test]
+    fn typing() {
+        let mut presences = Presences::new(AWAY_AFTER);
+        let [alice, bob] = ["Alice", "Bob"].map(|name| Id::new(name.to_owned()).unwrap());
+        assert!(presences.typing(&alice));
+        assert!(!presences.typing(&alice));
+        assert!(presences.typing(&bob));
+        presences.remove(&alice);
+        assert!(presences.typing(&alice));
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Serialize, Deserialize};
+use crate::id::Id;
+
+// Secs between batches of receipts, in both directions
+pub const RECEIPT_INTERVAL: f32 = 0.5;
+// Messages the server keeps receipts for, the oldest are dropped first
+pub const MAX_TRACKED: usize = 4096;
+// Receipts per packet, larger batches are split
+pub const MAX_RECEIPT_BATCH: usize = 128;
+
+// What happened to a unicast or multicast message for one recipient. Only moves forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Receipt {
+    // Sent by us, waiting for the server to relay it
+    Pending,
+    // Recipient is unknown, or has too many messages waiting
+    Failed,
+    // Recipient is offline, the server holds it
+    Queued,
+    // Handed to the recipient's connection
+    Sent,
+    // Recipient's client got it
+    Delivered,
+    Read
+}
+
+// A receipt for the sender, of one recipient
+pub type ReceiptUpdate = (u64, Id, Receipt);
+
+struct Tracked {
+    sender: Id,
+    recipients: HashMap<Id, Receipt>
+}
+
+// Receipts of recent messages, batched per sender
+pub struct Receipts {
+    tracked: HashMap<u64, Tracked>,
+    // Oldest first
+    order: VecDeque<u64>,
+    batches: HashMap<Id, Vec<ReceiptUpdate>>
+}
+
+impl Default for Receipts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Receipts {
+    pub fn new() -> Self {
+        Self {
+            tracked: HashMap::new(), order: VecDeque::new(), batches: HashMap::new()
+        }
+    }
+
+    pub fn track(&mut self, message: u64, sender: Id, recipients: Vec<(Id, Receipt)>) {
+        let recipients = recipients.into_iter()
+            .filter(|&(_, receipt)| receipt != Receipt::Failed)
+            .collect::<HashMap<_, _>>();
+        if recipients.is_empty() {
+            return
+        }
+        self.tracked.insert(message, Tracked {
+            sender, recipients
+        });
+        self.order.push_back(message);
+        while self.order.len() > MAX_TRACKED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.tracked.remove(&oldest);
+            }
+        }
+    }
+
+    // Queues the receipt for the sender. Returns false if it's unknown or no step forward.
+    pub fn acknowledge(&mut self, message: u64, recipient: &Id, receipt: Receipt) -> bool {
+        let tracked = match self.tracked.get_mut(&message) {
+            Some(tracked) => tracked,
+            None => return false
+        };
+        match tracked.recipients.get_mut(recipient) {
+            Some(current) if *current < receipt => *current = receipt,
+            _ => return false
+        }
+        self.batches.entry(tracked.sender.clone()).or_default()
+            .push((message, recipient.clone(), receipt));
+        // Nothing left to learn about this recipient
+        if receipt == Receipt::Read {
+            tracked.recipients.remove(recipient);
+            if tracked.recipients.is_empty() {
+                self.tracked.remove(&message);
+            }
+        }
+        true
+    }
+
+    // Receipts gathered since the last call, by sender
+    pub fn take(&mut self) -> HashMap<Id, Vec<ReceiptUpdate>> {
+        std::mem::take(&mut self.batches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::id::Id;
+    use super::{Receipts, Receipt};
+
+    #[test]
+    fn acknowledge() {
+        let mut receipts = Receipts::new();
+        let [alice, bob, carol, eve] = ["Alice", "Bob", "Carol", "Eve"].map(|name| Id::new(name.to_owned()).unwrap());
+        receipts.track(7, alice.clone(), vec![(bob.clone(), Receipt::Sent), (carol.clone(), Receipt::Queued), (eve.clone(), Receipt::Failed)]);
+        assert!(receipts.acknowledge(7, &bob, Receipt::Delivered));
+        assert!(receipts.acknowledge(7, &bob, Receipt::Read));
+        // Late or foreign ones
+        assert!(!receipts.acknowledge(7, &bob, Receipt::Delivered));
+        assert!(!receipts.acknowledge(7, &eve, Receipt::Read));
+        assert!(!receipts.acknowledge(8, &carol, Receipt::Read));
+        assert!(receipts.acknowledge(7, &carol, Receipt::Sent));
+
+        let batches = receipts.take();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[&alice], vec![(7, bob.clone(), Receipt::Delivered), (7, bob.clone(), Receipt::Read), (7, carol.clone(), Receipt::Sent)]);
+        assert!(receipts.take().is_empty());
+
+        // Forgotten once everyone read it
+        assert!(receipts.acknowledge(7, &carol, Receipt::Read));
+        assert!(!receipts.acknowledge(7, &carol, Receipt::Read));
+        assert!(receipts.tracked.is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use std::{collections::HashMap, time::{Instant, Duration}};
+use serde::{Serialize, Deserialize};
+use crate::{id::Id, packet::{RequestError, RoomInfo}, history::ChatEntry};
+
+pub const MAX_ROOM_NAME_LEN: usize = 24;
+pub const MAX_TOPIC_LEN: usize = 256;
+
+// Ordered by privilege. Everyone may only act on members below their own role.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Role {
+    Member,
+    // May change the topic while it's locked
+    Voiced,
+    // May kick, ban, mute, lock the topic and hand out voice
+    Operator,
+    // Opened the room, may also appoint operators
+    Owner
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModAction {
+    Kick(Id),
+    // Secs, 0 lifts the ban. Bans stick to the name, so they also hold while offline.
+    Ban(Id, u32),
+    // Secs, 0 unmutes
+    Mute(Id, u32),
+    LockTopic(bool),
+    SetRole(Id, Role)
+}
+
+impl ModAction {
+    // Peer the action is aimed at
+    pub fn target(&self) -> Option<&Id> {
+        match self {
+            ModAction::Kick(id) | ModAction::Ban(id, _) | ModAction::Mute(id, _) | ModAction::SetRole(id, _) => Some(id),
+            ModAction::LockTopic(_) => None
+        }
+    }
+
+    // Does the target lose its membership?
+    pub fn removes(&self) -> bool {
+        match self {
+            ModAction::Kick(_) => true,
+            ModAction::Ban(_, secs) => *secs > 0,
+            _ => false
+        }
+    }
+}
+
+fn remaining(until: Instant) -> u32 {
+    until.saturating_duration_since(Instant::now()).as_secs() as u32 + 1
+}
+
+pub struct Room {
+    topic: Option<String>,
+    topic_locked: bool,
+    members: HashMap<Id, Role>
+}
+
+impl Room {
+    fn new() -> Self {
+        Self {
+            topic: None, topic_locked: false, members: HashMap::new()
+        }
+    }
+
+    pub fn topic(&self) -> Option<&String> {
+        self.topic.as_ref()
+    }
+
+    pub fn topic_locked(&self) -> bool {
+        self.topic_locked
+    }
+
+    pub fn members(&self) -> &HashMap<Id, Role> {
+        &self.members
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.members.contains_key(id)
+    }
+
+    pub fn role(&self, id: &Id) -> Option<Role> {
+        self.members.get(id).copied()
+    }
+
+    fn moderate(&mut self, actor: Role, action: &ModAction) -> Result<(), RequestError> {
+        if actor < Role::Operator {
+            return Err(RequestError::NotPermitted)
+        }
+        if let Some(target) = action.target() {
+            // Bans may also hit peers that aren't in the room (anymore)
+            match (self.role(target), action) {
+                (Some(role), _) if role >= actor => return Err(RequestError::NotPermitted),
+                (None, ModAction::Ban(..)) => (),
+                (None, _) => return Err(RequestError::PeerNotInRoom(target.clone())),
+                _ => ()
+            }
+        }
+        match action {
+            ModAction::Kick(target) | ModAction::Ban(target, 1..) => {
+                self.members.remove(target);
+            },
+            // Bans and mutes are kept by the rooms
+            ModAction::Ban(..) | ModAction::Mute(..) => (),
+            ModAction::LockTopic(locked) => self.topic_locked = *locked,
+            ModAction::SetRole(target, role) => {
+                if *role >= actor {
+                    return Err(RequestError::NotPermitted)
+                }
+                self.members.insert(target.clone(), *role);
+            }
+        }
+        Ok(())
+    }
+}
+
+// All rooms of a server. Rooms are opened by their first member, who owns them, and
+// closed once the last one left. Bans and mutes outlive both, until they expire.
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    // By room, by name, until
+    bans: HashMap<String, HashMap<String, Instant>>,
+    // By room, until
+    mutes: HashMap<String, HashMap<Id, Instant>>
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self {
+            rooms: HashMap::new(), bans: HashMap::new(), mutes: HashMap::new()
+        }
+    }
+
+    pub fn verify_name(name: &str) -> Result<(), RequestError> {
+        if name.is_empty() || name.len() > MAX_ROOM_NAME_LEN
+            || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            Err(RequestError::InvalidRoomName(name.to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    // Secs left, if banned from the room
+    pub fn banned(&self, room: &str, name: &str) -> Option<u32> {
+        self.bans.get(room).and_then(|bans| bans.get(name))
+            .filter(|&&until| Instant::now() < until).map(|&until| remaining(until))
+    }
+
+    pub fn muted(&self, room: &str, id: &Id) -> Option<u32> {
+        self.mutes.get(room).and_then(|mutes| mutes.get(id))
+            .filter(|&&until| Instant::now() < until).map(|&until| remaining(until))
+    }
+
+    // Forget bans and mutes that ran out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        for bans in self.bans.values_mut() {
+            bans.retain(|_, &mut until| now < until);
+        }
+        self.bans.retain(|_, bans| !bans.is_empty());
+        for mutes in self.mutes.values_mut() {
+            mutes.retain(|_, &mut until| now < until);
+        }
+        self.mutes.retain(|_, mutes| !mutes.is_empty());
+    }
+
+    // Room the peer must be a member of
+    pub fn joined(&self, name: &str, id: &Id) -> Result<&Room, RequestError> {
+        self.rooms.get(name).filter(|room| room.contains(id))
+            .ok_or_else(|| RequestError::NotInRoom(name.to_owned()))
+    }
+
+    // Room the peer wants to write to
+    pub fn speaker(&self, name: &str, id: &Id) -> Result<&Room, RequestError> {
+        let room = self.joined(name, id)?;
+        match self.muted(name, id) {
+            Some(secs) => Err(RequestError::Muted(name.to_owned(), secs)),
+            None => Ok(room)
+        }
+    }
+
+    // Room the peer wants to edit or delete a message of the author in. Operators may do so
+    // with the messages of everyone below them.
+    pub fn redactor(&self, name: &str, id: &Id, author: &Id) -> Result<&Room, RequestError> {
+        let room = self.joined(name, id)?;
+        let actor = room.role(id).unwrap();
+        if id != author && (actor < Role::Operator || room.role(author).is_some_and(|role| role >= actor)) {
+            Err(RequestError::NotPermitted)
+        } else {
+            Ok(room)
+        }
+    }
+
+    pub fn join(&mut self, name: &str, id: Id) -> Result<&Room, RequestError> {
+        Self::verify_name(name)?;
+        if let Some(secs) = self.banned(name, id.name()) {
+            return Err(RequestError::Banned(name.to_owned(), secs))
+        }
+        let room = self.rooms.entry(name.to_owned()).or_insert_with(Room::new);
+        let role = if room.members.is_empty() {
+            Role::Owner
+        } else {
+            Role::Member
+        };
+        room.members.entry(id).or_insert(role);
+        Ok(room)
+    }
+
+    pub fn leave(&mut self, name: &str, id: &Id) -> Result<(), RequestError> {
+        let room = self.rooms.get_mut(name)
+            .filter(|room| room.contains(id))
+            .ok_or_else(|| RequestError::NotInRoom(name.to_owned()))?;
+        room.members.remove(id);
+        if room.members.is_empty() {
+            self.rooms.remove(name);
+        }
+        Ok(())
+    }
+
+    // Drop a disconnected peer from every room. Returns the rooms it was in.
+    pub fn leave_all(&mut self, id: &Id) -> Vec<String> {
+        let names = self.rooms.iter()
+            .filter(|(_, room)| room.contains(id))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        for name in names.iter() {
+            let _ = self.leave(name, id);
+        }
+        names
+    }
+
+    pub fn set_topic(&mut self, name: &str, id: &Id, topic: String) -> Result<(), RequestError> {
+        if topic.len() > MAX_TOPIC_LEN {
+            return Err(RequestError::TopicTooLong(MAX_TOPIC_LEN))
+        }
+        let room = self.joined(name, id)?;
+        if room.topic_locked && room.role(id) < Some(Role::Voiced) {
+            return Err(RequestError::TopicLocked(name.to_owned()))
+        }
+        let room = self.rooms.get_mut(name).unwrap();
+        room.topic = if topic.is_empty() {
+            None
+        } else {
+            Some(topic)
+        };
+        Ok(())
+    }
+
+    pub fn moderate(&mut self, name: &str, id: &Id, action: &ModAction) -> Result<(), RequestError> {
+        let actor = self.joined(name, id)?.role(id).unwrap();
+        self.rooms.get_mut(name).unwrap().moderate(actor, action)?;
+        let until = |secs: u32| Instant::now() + Duration::from_secs(secs as u64);
+        match action {
+            ModAction::Ban(target, 0) => {
+                if let Some(bans) = self.bans.get_mut(name) {
+                    bans.remove(target.name());
+                }
+            },
+            ModAction::Ban(target, secs) => {
+                self.bans.entry(name.to_owned()).or_default().insert(target.name().to_owned(), until(*secs));
+            },
+            ModAction::Mute(target, 0) => {
+                if let Some(mutes) = self.mutes.get_mut(name) {
+                    mutes.remove(target);
+                }
+            },
+            ModAction::Mute(target, secs) => {
+                self.mutes.entry(name.to_owned()).or_default().insert(target.clone(), until(*secs));
+            },
+            _ => ()
+        }
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<RoomInfo> {
+        let mut list = self.rooms.iter().map(|(name, room)| RoomInfo {
+            name: name.clone(), topic: room.topic.clone(), members: room.members.len() as u32
+        }).collect::<Vec<_>>();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+}
+
+// A room as seen by one of its members
+#[derive(Debug, Clone)]
+pub struct RoomView {
+    pub topic: Option<String>,
+    pub topic_locked: bool,
+    pub role: Role,
+    pub peers: HashMap<Id, Role>,
+    pub chat_log: Vec<ChatEntry>
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, packet::RequestError};
+    use super::{Rooms, Role, ModAction};
+
+    #[test]
+    fn membership() {
+        let mut rooms = Rooms::new();
+        let alice = Id::new("Alice".to_owned()).unwrap();
+        let bob = Id::new("Bob".to_owned()).unwrap();
+        rooms.join("rust", alice.clone()).unwrap();
+        rooms.join("rust", bob.clone()).unwrap();
+        rooms.join("off-topic", alice.clone()).unwrap();
+        assert_eq!(rooms.join("no spaces", bob.clone()).err(),
+            Some(RequestError::InvalidRoomName("no spaces".to_owned())));
+        assert_eq!(rooms.set_topic("off-topic", &bob, "Cats".to_owned()),
+            Err(RequestError::NotInRoom("off-topic".to_owned())));
+        rooms.set_topic("rust", &bob, "Lifetimes".to_owned()).unwrap();
+
+        let list = rooms.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!((list[1].name.as_str(), list[1].topic.as_deref(), list[1].members), ("rust", Some("Lifetimes"), 2));
+
+        let mut left = rooms.leave_all(&alice);
+        left.sort();
+        assert_eq!(left, vec!["off-topic".to_owned(), "rust".to_owned()]);
+        // Last member gone
+        assert!(rooms.get("off-topic").is_none());
+        assert_eq!(rooms.get("rust").unwrap().members().len(), 1);
+        assert!(rooms.leave("rust", &alice).is_err());
+    }
+
+    #[test]
+    fn moderation() {
+        let mut rooms = Rooms::new();
+        let [owner, op, bob, eve] = ["Owner", "Oper", "Bob", "Eve"].map(|name| Id::new(name.to_owned()).unwrap());
+        for id in [&owner, &op, &bob, &eve] {
+            rooms.join("rust", id.clone()).unwrap();
+        }
+        let room = rooms.get("rust").unwrap();
+        assert_eq!((room.role(&owner), room.role(&bob)), (Some(Role::Owner), Some(Role::Member)));
+
+        // Only the owner appoints operators, nobody may act on equals
+        assert_eq!(rooms.moderate("rust", &bob, &ModAction::Kick(eve.clone())), Err(RequestError::NotPermitted));
+        rooms.moderate("rust", &owner, &ModAction::SetRole(op.clone(), Role::Operator)).unwrap();
+        assert_eq!(rooms.moderate("rust", &op, &ModAction::SetRole(bob.clone(), Role::Operator)), Err(RequestError::NotPermitted));
+        assert_eq!(rooms.moderate("rust", &op, &ModAction::Kick(owner.clone())), Err(RequestError::NotPermitted));
+        rooms.moderate("rust", &op, &ModAction::SetRole(bob.clone(), Role::Voiced)).unwrap();
+
+        // Everyone may delete their own messages, operators those of members below them
+        assert!(rooms.redactor("rust", &eve, &eve).is_ok());
+        assert_eq!(rooms.redactor("rust", &eve, &bob).err(), Some(RequestError::NotPermitted));
+        assert!(rooms.redactor("rust", &op, &bob).is_ok());
+        assert_eq!(rooms.redactor("rust", &op, &owner).err(), Some(RequestError::NotPermitted));
+
+        rooms.moderate("rust", &op, &ModAction::LockTopic(true)).unwrap();
+        assert_eq!(rooms.set_topic("rust", &eve, "Spam".to_owned()), Err(RequestError::TopicLocked("rust".to_owned())));
+        rooms.set_topic("rust", &bob, "Lifetimes".to_owned()).unwrap();
+
+        rooms.moderate("rust", &op, &ModAction::Mute(eve.clone(), 60)).unwrap();
+        assert!(matches!(rooms.speaker("rust", &eve), Err(RequestError::Muted(_, 60))));
+        rooms.moderate("rust", &op, &ModAction::Mute(eve.clone(), 0)).unwrap();
+        assert!(rooms.speaker("rust", &eve).is_ok());
+
+        rooms.moderate("rust", &op, &ModAction::Ban(eve.clone(), 60)).unwrap();
+        assert!(!rooms.get("rust").unwrap().contains(&eve));
+        // Name is banned, whatever the sign
+        let eve_again = Id::new("Eve".to_owned()).unwrap();
+        assert!(matches!(rooms.join("rust", eve_again.clone()), Err(RequestError::Banned(_, 60))));
+        rooms.moderate("rust", &op, &ModAction::Ban(eve.clone(), 0)).unwrap();
+        rooms.join("rust", eve_again.clone()).unwrap();
+        rooms.moderate("rust", &op, &ModAction::Kick(eve_again.clone())).unwrap();
+        assert!(!rooms.get("rust").unwrap().contains(&eve_again));
+    }
+
+    #[test]
+    fn sanctions_outlive_membership() {
+        let mut rooms = Rooms::new();
+        let [owner, bob, eve] = ["Owner", "Bob", "Eve"].map(|name| Id::new(name.to_owned()).unwrap());
+        for id in [&owner, &bob, &eve] {
+            rooms.join("rust", id.clone()).unwrap();
+        }
+        rooms.moderate("rust", &owner, &ModAction::Mute(bob.clone(), 60)).unwrap();
+        rooms.moderate("rust", &owner, &ModAction::Ban(eve.clone(), 60)).unwrap();
+        // Leaving doesn't lift the mute
+        rooms.leave("rust", &bob).unwrap();
+        rooms.join("rust", bob.clone()).unwrap();
+        assert!(matches!(rooms.speaker("rust", &bob), Err(RequestError::Muted(_, 60))));
+
+        // Neither does closing the room
+        rooms.leave_all(&owner);
+        rooms.leave_all(&bob);
+        assert!(rooms.get("rust").is_none());
+        rooms.expire();
+        assert!(matches!(rooms.join("rust", eve.clone()), Err(RequestError::Banned(_, 60))));
+        rooms.join("rust", bob.clone()).unwrap();
+        assert!(matches!(rooms.speaker("rust", &bob), Err(RequestError::Muted(_, 60))));
+
+        // Only pruned once they ran out
+        rooms.moderate("rust", &bob, &ModAction::Ban(eve.clone(), 1)).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        rooms.expire();
+        assert!(rooms.bans.is_empty());
+        rooms.join("rust", eve).unwrap();
+    }
+}
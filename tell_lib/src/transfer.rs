@@ -0,0 +1,493 @@
+use std::{collections::{HashMap, VecDeque}, time::{Instant, Duration}};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::{id::Id, packet::RequestError, codec::CodecKind, net::{adapter::UDP_READ_BUF_SIZE, fragment::UDP_FRAGMENT_SIZE}};
+
+// Bytes per chunk with the binary codecs, a chunk packet with its header still fits into one datagram
+pub const FILE_CHUNK_SIZE: usize = UDP_READ_BUF_SIZE - 128;
+// Smallest chunks a sender may pick, so a file doesn't take forever
+pub const MIN_FILE_CHUNK_SIZE: usize = 64;
+// Default limits of the server
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+// Bytes per sec the server relays, over all transfers
+pub const RELAY_RATE: u64 = 1024 * 1024;
+// Chunks a sender may be ahead of the last ack
+pub const FILE_WINDOW: u32 = 32;
+// Receivers ack every few chunks, and the last one
+pub const FILE_ACK_EVERY: u32 = 8;
+pub const MAX_FILE_NAME_LEN: usize = 255;
+// Offers of a sender the receivers didn't accept yet
+pub const MAX_PENDING_OFFERS: usize = 4;
+// Secs a transfer is kept without any chunk, ack or resume
+pub const TRANSFER_TIMEOUT: f32 = 10. * 60.;
+
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+    crc.sum()
+}
+
+pub fn hash(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+// Chunks shrink with the codec's fragments, so they still fit into one datagram with Json.
+// Json spells out the field names too and needs more room besides the bytes.
+pub fn chunk_size(codec: CodecKind) -> usize {
+    let header = match codec {
+        CodecKind::Json => 192,
+        _ => UDP_FRAGMENT_SIZE - FILE_CHUNK_SIZE
+    };
+    (UDP_FRAGMENT_SIZE - header) * codec.fragment_size() / UDP_FRAGMENT_SIZE
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    // Bytes
+    pub size: u64,
+    // Sha256 of the whole file
+    #[serde(with = "serde_bytes")]
+    pub hash: Vec<u8>,
+    // Bytes per chunk, picked by the sender for the codec
+    pub chunk_size: u32
+}
+
+impl FileInfo {
+    pub fn of(name: String, data: &[u8], codec: CodecKind) -> Self {
+        Self {
+            name, size: data.len() as u64, hash: hash(data), chunk_size: chunk_size(codec) as u32
+        }
+    }
+
+    pub fn chunks(&self) -> u32 {
+        self.size.div_ceil(self.chunk_size as u64) as u32
+    }
+
+    fn valid(&self) -> bool {
+        !self.name.is_empty() && self.name.len() <= MAX_FILE_NAME_LEN && self.hash.len() == 32
+            && (MIN_FILE_CHUNK_SIZE..=FILE_CHUNK_SIZE).contains(&(self.chunk_size as usize))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub transfer: u64,
+    pub index: u32,
+    // Crc32 of the bytes
+    pub checksum: u32,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>
+}
+
+impl Chunk {
+    pub fn new(transfer: u64, index: u32, bytes: Vec<u8>) -> Self {
+        Self {
+            transfer, index, checksum: checksum(&bytes), bytes
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        self.bytes.len() <= FILE_CHUNK_SIZE && checksum(&self.bytes) == self.checksum
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferState {
+    // Waiting for the server to hand out an id, or for the receiver
+    Offered,
+    Active,
+    // Hash checked, or fully acked by the receiver
+    Completed,
+    // Rejected by the server, or the hash didn't match
+    Failed,
+    Cancelled
+}
+
+// A file we send or receive, kept across reconnects so it can resume
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    // Known once the server relayed the offer
+    pub id: Option<u64>,
+    pub peer: Id,
+    pub incoming: bool,
+    pub file: FileInfo,
+    // Whole file when sending, what arrived so far when receiving
+    pub data: Vec<u8>,
+    // Next chunk to send, or expected
+    pub next: u32,
+    // Chunks the receiver confirmed
+    pub acked: u32,
+    pub state: TransferState
+}
+
+impl Transfer {
+    pub fn outgoing(peer: Id, name: String, data: Vec<u8>, codec: CodecKind) -> Self {
+        Self {
+            id: None, peer, incoming: false, file: FileInfo::of(name, &data, codec), data, next: 0, acked: 0, state: TransferState::Offered
+        }
+    }
+
+    pub fn incoming(id: u64, peer: Id, file: FileInfo) -> Self {
+        Self {
+            id: Some(id), peer, incoming: true, file, data: vec![], next: 0, acked: 0, state: TransferState::Offered
+        }
+    }
+
+    // Bytes that made it to the receiver
+    pub fn progress(&self) -> u64 {
+        if self.incoming {
+            self.data.len() as u64
+        } else {
+            (self.acked as u64 * self.file.chunk_size as u64).min(self.file.size)
+        }
+    }
+
+    // Next chunk the window allows us to send
+    pub fn next_chunk(&mut self) -> Option<Chunk> {
+        match self.id {
+            Some(id) if self.state == TransferState::Active && self.next < self.file.chunks()
+                && self.next < self.acked + FILE_WINDOW => {
+                let chunk_size = self.file.chunk_size as usize;
+                let start = self.next as usize * chunk_size;
+                let end = (start + chunk_size).min(self.data.len());
+                let chunk = Chunk::new(id, self.next, self.data[start..end].to_vec());
+                self.next += 1;
+                Some(chunk)
+            },
+            _ => None
+        }
+    }
+
+    // Takes the chunk if it's the expected one
+    pub fn receive(&mut self, chunk: Chunk) -> Received {
+        if chunk.index != self.next {
+            Received::Skipped
+        } else if !chunk.verify() || chunk.bytes.len() > self.file.chunk_size as usize
+            || self.data.len() + chunk.bytes.len() > self.file.size as usize {
+            Received::Broken
+        } else {
+            self.data.extend(chunk.bytes);
+            self.next += 1;
+            Received::Taken
+        }
+    }
+
+    // Got everything
+    pub fn done(&self) -> bool {
+        self.next >= self.file.chunks()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Received {
+    Taken,
+    // Not the expected one, like duplicates after a resume
+    Skipped,
+    // Has to be sent again
+    Broken
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransferEvent {
+    // Peer wants to send us a file, accept or cancel it
+    Offered(u64),
+    // Bytes that made it so far
+    Progress(u64, u64),
+    Completed(u64),
+    // Hash of the received file didn't match
+    Corrupted(u64),
+    // By the peer, or the server
+    Cancelled(u64),
+    // Offer the server refused, by file name
+    Rejected(String, RequestError)
+}
+
+struct Relayed {
+    source: Id,
+    target: Id,
+    chunks: u32,
+    chunk_size: usize,
+    accepted: bool,
+    acked: u32,
+    // Next chunk expected from the source, earlier ones were queued or relayed already
+    next: u32,
+    // Waiting for bandwidth
+    queue: VecDeque<Chunk>,
+    active: Instant
+}
+
+// Transfers relayed by the server. Chunks are only taken within the window and
+// handed on at the relay rate, so nothing piles up.
+pub struct Relay {
+    transfers: HashMap<u64, Relayed>,
+    next_transfer: u64,
+    max_file_size: u64,
+    // Bytes per sec
+    rate: u64,
+    tokens: f32,
+    refilled: Instant
+}
+
+impl Relay {
+    pub fn new(max_file_size: u64, rate: u64) -> Self {
+        Self {
+            transfers: HashMap::new(), next_transfer: 0, max_file_size, rate, tokens: 0., refilled: Instant::now()
+        }
+    }
+
+    // Only new offers are held to a new max size
+    pub fn limit(&mut self, max_file_size: u64, rate: u64) {
+        self.max_file_size = max_file_size;
+        self.rate = rate;
+    }
+
+    pub fn offer(&mut self, source: Id, target: Id, file: &FileInfo) -> Result<u64, RequestError> {
+        if file.size > self.max_file_size {
+            return Err(RequestError::FileTooLarge(self.max_file_size))
+        }
+        if !file.valid() {
+            return Err(RequestError::InvalidFile)
+        }
+        if self.transfers.values().filter(|transfer| transfer.source == source && !transfer.accepted).count() >= MAX_PENDING_OFFERS {
+            return Err(RequestError::TooManyOffers(MAX_PENDING_OFFERS))
+        }
+        let id = self.next_transfer;
+        self.next_transfer += 1;
+        self.transfers.insert(id, Relayed {
+            source, target, chunks: file.chunks(), chunk_size: file.chunk_size as usize, accepted: false, acked: 0, next: 0, queue: VecDeque::new(), active: Instant::now()
+        });
+        Ok(id)
+    }
+
+    // Receiver wants the chunks from the index on. Returns the sender.
+    pub fn accept(&mut self, id: u64, target: &Id, from: u32) -> Result<Id, RequestError> {
+        match self.transfers.get_mut(&id) {
+            Some(transfer) if &transfer.target == target && from <= transfer.chunks => {
+                transfer.accepted = true;
+                transfer.acked = from;
+                // Sent again from there
+                transfer.next = from;
+                transfer.queue.clear();
+                transfer.active = Instant::now();
+                Ok(transfer.source.clone())
+            },
+            _ => Err(RequestError::UnknownTransfer(id))
+        }
+    }
+
+    // Queues the chunk if it's the next one and in the window. Chunks come in order, so anything
+    // else was sent before or is still in flight from before the last accept. Returns false if dropped.
+    pub fn chunk(&mut self, source: &Id, chunk: Chunk) -> bool {
+        match self.transfers.get_mut(&chunk.transfer) {
+            Some(transfer) if &transfer.source == source && transfer.accepted
+                && chunk.index == transfer.next && chunk.index < transfer.acked + FILE_WINDOW
+                && chunk.index < transfer.chunks && chunk.bytes.len() <= transfer.chunk_size
+                && transfer.queue.len() < FILE_WINDOW as usize => {
+                transfer.active = Instant::now();
+                transfer.next = chunk.index + 1;
+                transfer.queue.push_back(chunk);
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Returns the sender and if the transfer is complete
+    pub fn ack(&mut self, id: u64, target: &Id, next: u32) -> Option<(Id, bool)> {
+        let transfer = self.transfers.get_mut(&id).filter(|transfer| &transfer.target == target)?;
+        transfer.acked = next.clamp(transfer.acked, transfer.chunks);
+        transfer.active = Instant::now();
+        let source = transfer.source.clone();
+        let complete = transfer.acked == transfer.chunks;
+        if complete {
+            self.transfers.remove(&id);
+        }
+        Some((source, complete))
+    }
+
+    // Either side may cancel. Returns the other one.
+    pub fn cancel(&mut self, id: u64, by: &Id) -> Option<Id> {
+        let transfer = self.transfers.get(&id)?;
+        let other = if &transfer.source == by {
+            transfer.target.clone()
+        } else if &transfer.target == by {
+            transfer.source.clone()
+        } else {
+            return None
+        };
+        self.transfers.remove(&id);
+        Some(other)
+    }
+
+    // Chunks for a peer that's gone are sent again once it accepts anew
+    pub fn disconnected(&mut self, id: &Id) {
+        for transfer in self.transfers.values_mut().filter(|transfer| &transfer.target == id) {
+            transfer.queue.clear();
+        }
+    }
+
+    // Accepted transfers of a sender and where to go on from
+    pub fn resumable(&self, source: &Id) -> Vec<(u64, u32)> {
+        self.transfers.iter()
+            .filter(|(_, transfer)| &transfer.source == source && transfer.accepted)
+            .map(|(&id, transfer)| (id, transfer.acked))
+            .collect()
+    }
+
+    // Sender came back, it sends everything after the last ack again
+    pub fn resume(&mut self, source: &Id) -> Vec<(u64, u32)> {
+        for transfer in self.transfers.values_mut().filter(|transfer| &transfer.source == source && transfer.accepted) {
+            transfer.next = transfer.acked;
+            transfer.queue.clear();
+        }
+        self.resumable(source)
+    }
+
+    // Chunks that may go out now, taken from each transfer in turn
+    pub fn relay(&mut self) -> Vec<(Id, Chunk)> {
+        let burst = self.rate.max(FILE_CHUNK_SIZE as u64) as f32;
+        self.tokens = (self.tokens + self.refilled.elapsed().as_secs_f32() * self.rate as f32).min(burst);
+        self.refilled = Instant::now();
+        let mut chunks = vec![];
+        loop {
+            let mut relayed = false;
+            for transfer in self.transfers.values_mut() {
+                match transfer.queue.front() {
+                    Some(chunk) if chunk.bytes.len() as f32 <= self.tokens => {
+                        self.tokens -= chunk.bytes.len() as f32;
+                        chunks.push((transfer.target.clone(), transfer.queue.pop_front().unwrap()));
+                        relayed = true;
+                    },
+                    _ => ()
+                }
+            }
+            if !relayed {
+                return chunks
+            }
+        }
+    }
+
+    // Drops transfers nobody cared about for long. Returns them with source and target.
+    pub fn expire(&mut self) -> Vec<(u64, Id, Id)> {
+        let timeout = Duration::from_secs_f32(TRANSFER_TIMEOUT);
+        let expired = self.transfers.iter()
+            .filter(|(_, transfer)| transfer.active.elapsed() >= timeout)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        expired.into_iter()
+            .filter_map(|id| self.transfers.remove(&id).map(|transfer| (id, transfer.source, transfer.target)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{id::Id, codec::CodecKind, builder::PacketBuilder, packet::{PacketType, ClientPacket, ServerPacket, RequestError}, net::fragment::UDP_FRAGMENT_SIZE};
+    use super::{Relay, Transfer, TransferState, Received, Chunk, FileInfo, chunk_size, FILE_CHUNK_SIZE, MIN_FILE_CHUNK_SIZE, FILE_WINDOW, MAX_PENDING_OFFERS};
+
+    #[test]
+    fn chunk_fits() {
+        let id = Id::new("Alice12345".to_owned()).unwrap();
+        for codec in [CodecKind::MessagePack, CodecKind::Bincode, CodecKind::Json] {
+            assert!(chunk_size(codec) >= MIN_FILE_CHUNK_SIZE);
+            let chunk = Chunk::new(u64::MAX, u32::MAX, vec![0xff; chunk_size(codec)]);
+            let builder = PacketBuilder::new(id.clone(), codec);
+            for packet in [PacketType::Client(ClientPacket::Chunk(chunk.clone())), PacketType::Server(ServerPacket::Chunk(chunk.clone()))] {
+                assert!(builder.serialize(packet).unwrap().len() <= UDP_FRAGMENT_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn transfer() {
+        let [alice, bob] = ["Alice", "Bob"].map(|name| Id::new(name.to_owned()).unwrap());
+        let data = (0..FILE_CHUNK_SIZE * 2 + 7).map(|i| i as u8).collect::<Vec<_>>();
+        let mut outgoing = Transfer::outgoing(bob.clone(), "log.txt".to_owned(), data.clone(), CodecKind::MessagePack);
+        assert_eq!(outgoing.file.chunks(), 3);
+        assert_eq!(outgoing.next_chunk(), None);
+        outgoing.id = Some(0);
+        outgoing.state = TransferState::Active;
+        let mut incoming = Transfer::incoming(0, alice.clone(), outgoing.file.clone());
+        let first = outgoing.next_chunk().unwrap();
+        let mut broken = first.clone();
+        broken.bytes[0] ^= 1;
+        assert_eq!(incoming.receive(broken), Received::Broken);
+        assert_eq!(incoming.receive(first.clone()), Received::Taken);
+        // Duplicate after a resume
+        assert_eq!(incoming.receive(first), Received::Skipped);
+        while let Some(chunk) = outgoing.next_chunk() {
+            assert_eq!(incoming.receive(chunk), Received::Taken);
+        }
+        assert!(incoming.done());
+        assert_eq!(incoming.data, data);
+        assert_eq!(FileInfo::of("log.txt".to_owned(), &incoming.data, CodecKind::MessagePack), outgoing.file);
+
+        // Smaller chunks with Json, and none bigger than the sender said
+        let mut outgoing = Transfer::outgoing(bob.clone(), "log.txt".to_owned(), data.clone(), CodecKind::Json);
+        assert_eq!(outgoing.file.chunks(), data.len().div_ceil(chunk_size(CodecKind::Json)) as u32);
+        outgoing.id = Some(1);
+        outgoing.state = TransferState::Active;
+        let mut incoming = Transfer::incoming(1, alice.clone(), outgoing.file.clone());
+        assert_eq!(incoming.receive(Chunk::new(1, 0, data[..FILE_CHUNK_SIZE].to_vec())), Received::Broken);
+        while let Some(chunk) = outgoing.next_chunk() {
+            assert_eq!(incoming.receive(chunk), Received::Taken);
+        }
+        assert_eq!(incoming.data, data);
+    }
+
+    #[test]
+    fn relay() {
+        let [alice, bob] = ["Alice", "Bob"].map(|name| Id::new(name.to_owned()).unwrap());
+        let mut relay = Relay::new(FILE_CHUNK_SIZE as u64 * 64, FILE_CHUNK_SIZE as u64 * 10);
+        let file = FileInfo::of("log.txt".to_owned(), &vec![1; FILE_CHUNK_SIZE * 64], CodecKind::MessagePack);
+        assert_eq!(relay.offer(alice.clone(), bob.clone(), &FileInfo::of("big".to_owned(), &vec![1; FILE_CHUNK_SIZE * 64 + 1], CodecKind::MessagePack)),
+            Err(RequestError::FileTooLarge(FILE_CHUNK_SIZE as u64 * 64)));
+        for chunk_size in [0, MIN_FILE_CHUNK_SIZE as u32 - 1, FILE_CHUNK_SIZE as u32 + 1] {
+            assert_eq!(relay.offer(alice.clone(), bob.clone(), &FileInfo { chunk_size, ..file.clone() }), Err(RequestError::InvalidFile));
+        }
+        let id = relay.offer(alice.clone(), bob.clone(), &file).unwrap();
+        let chunk = |index| Chunk::new(id, index, vec![1; FILE_CHUNK_SIZE]);
+        // Not accepted yet, or not by the target
+        assert!(!relay.chunk(&alice, chunk(0)));
+        assert_eq!(relay.accept(id, &alice, 0), Err(RequestError::UnknownTransfer(id)));
+        assert_eq!(relay.accept(id, &bob, 0), Ok(alice.clone()));
+        assert!(!relay.chunk(&bob, chunk(0)));
+        for index in 0..FILE_WINDOW {
+            assert!(relay.chunk(&alice, chunk(index)));
+        }
+        assert!(!relay.chunk(&alice, chunk(FILE_WINDOW)));
+        // Queued already
+        assert!(!relay.chunk(&alice, chunk(3)));
+        assert_eq!(relay.resumable(&alice), vec![(id, 0)]);
+
+        // Held back by the rate
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let relayed = relay.relay().len() as u32;
+        assert!((1..FILE_WINDOW).contains(&relayed));
+        assert_eq!(relay.ack(id, &bob, relayed), Some((alice.clone(), false)));
+        // Relayed already
+        assert!(!relay.chunk(&alice, chunk(0)));
+        assert!(relay.chunk(&alice, chunk(FILE_WINDOW)));
+        // Sender reconnected and starts over from the ack
+        assert_eq!(relay.resume(&alice), vec![(id, relayed)]);
+        assert!(relay.chunk(&alice, chunk(relayed)));
+        // Receiver asks again, what the sender still had in flight is dropped until it caught up
+        assert_eq!(relay.accept(id, &bob, relayed), Ok(alice.clone()));
+        assert!(!relay.chunk(&alice, chunk(relayed + 1)));
+        assert!(relay.chunk(&alice, chunk(relayed)));
+        assert_eq!(relay.ack(id, &bob, 64), Some((alice.clone(), true)));
+        assert!(relay.resumable(&alice).is_empty());
+
+        let id = relay.offer(alice.clone(), bob.clone(), &file).unwrap();
+        assert_eq!(relay.cancel(id, &Id::new("Eve".to_owned()).unwrap()), None);
+        assert_eq!(relay.cancel(id, &bob), Some(alice.clone()));
+        assert_eq!(relay.cancel(id, &alice), None);
+
+        for _ in 0..MAX_PENDING_OFFERS {
+            relay.offer(alice.clone(), bob.clone(), &file).unwrap();
+        }
+        assert_eq!(relay.offer(alice.clone(), bob.clone(), &file), Err(RequestError::TooManyOffers(MAX_PENDING_OFFERS)));
+        relay.offer(bob.clone(), alice.clone(), &file).unwrap();
+    }
+}
@@ -1,13 +1,39 @@
 use core::fmt;
 use std::time::{SystemTime, UNIX_EPOCH, Instant};
+use crate::err::{TResult, TellErr, LibErr};
 
 pub fn timestamp() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos()
 }
 
+// LEB128: 7 bits per byte, high bit set on all but the last byte
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn read_varint(buf: &mut &[u8]) -> TResult<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf.split_first()
+            .ok_or(TellErr::Lib(LibErr::InvalidHeader))?;
+        *buf = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value)
+        }
+    }
+    Err(TellErr::Lib(LibErr::InvalidHeader))
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Metrics {
     pub bytes_transfer: u128,
+    // Difference to the uncompressed size
+    pub bytes_saved: i128,
     pub packets_transfer: u64,
     pub last_transfer: Instant
 }
@@ -15,20 +41,135 @@ pub struct Metrics {
 impl Metrics {
     pub fn new() -> Metrics {
         Metrics {
-            bytes_transfer: 0, packets_transfer: 0, last_transfer: Instant::now()
+            bytes_transfer: 0, bytes_saved: 0, packets_transfer: 0, last_transfer: Instant::now()
         }
     }
 
     pub fn transfer(&mut self, size: usize) {
+        self.count(size);
+        self.last_transfer = Instant::now();
+    }
+
+    // Counted, but not activity, e.g. a heartbeat that only keeps the connection alive
+    pub fn count(&mut self, size: usize) {
         self.bytes_transfer += size as u128;
         self.packets_transfer += 1;
-        self.last_transfer = Instant::now();
+    }
+
+    pub fn compress(&mut self, raw_size: usize, size: usize) {
+        self.bytes_saved += raw_size as i128 - size as i128;
     }
 }
 
 impl fmt::Debug for Metrics {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} packets ({}b) since {:.2}s", self.packets_transfer, self.bytes_transfer,
-            self.last_transfer.elapsed().as_secs_f32())
+        write!(f, "{} packets ({}b, {}b saved) since {:.2}s", self.packets_transfer, self.bytes_transfer,
+            self.bytes_saved, self.last_transfer.elapsed().as_secs_f32())
+    }
+}
+
+// Link quality estimates (RFC 6298 style smoothing)
+#[derive(Clone, Copy, PartialEq)]
+pub struct LinkMetrics {
+    // Smoothed round trip time and its variance (secs)
+    pub rtt: f32,
+    pub rtt_var: f32,
+    // Smoothed difference between consecutive rtt samples (secs)
+    pub jitter: f32,
+    // Estimated share of lost reliable packets [0, 1]
+    pub loss: f32,
+    pub rtt_samples: u64,
+    // Congestion window and reliable packets currently in flight
+    pub window: f32,
+    pub in_flight: usize,
+    // Packets waiting for the window/pacing
+    pub queued: usize,
+    last_rtt: f32
+}
+
+impl Default for LinkMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkMetrics {
+    pub fn new() -> LinkMetrics {
+        LinkMetrics {
+            rtt: 0., rtt_var: 0., jitter: 0., loss: 0., rtt_samples: 0,
+            window: 0., in_flight: 0, queued: 0, last_rtt: 0.
+        }
+    }
+
+    pub fn rtt_sample(&mut self, rtt: f32) {
+        if self.rtt_samples == 0 {
+            self.rtt = rtt;
+            self.rtt_var = rtt / 2.;
+        } else {
+            self.rtt_var = 0.75 * self.rtt_var + 0.25 * (self.rtt - rtt).abs();
+            self.rtt = 0.875 * self.rtt + 0.125 * rtt;
+            self.jitter += ((self.last_rtt - rtt).abs() - self.jitter) / 16.;
+        }
+        self.last_rtt = rtt;
+        self.rtt_samples += 1;
+    }
+
+    pub fn loss_sample(&mut self, lost: bool) {
+        let sample = if lost { 1. } else { 0. };
+        self.loss += (sample - self.loss) / 32.;
+    }
+}
+
+impl fmt::Debug for LinkMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rtt {:.1}ms (var {:.1}ms), jitter {:.1}ms, loss {:.1}%, window {:.1} ({} in flight, {} queued)",
+            self.rtt * 1000., self.rtt_var * 1000., self.jitter * 1000., self.loss * 100.,
+            self.window, self.in_flight, self.queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LinkMetrics, Metrics, write_varint, read_varint};
+
+    #[test]
+    fn varint() {
+        let mut buf = vec![];
+        for value in [0, 127, 128, 300, u64::MAX] {
+            write_varint(&mut buf, value);
+        }
+        assert_eq!(buf.len(), 1 + 1 + 2 + 2 + 10);
+        let mut bytes = &buf[..];
+        for value in [0, 127, 128, 300, u64::MAX] {
+            assert_eq!(read_varint(&mut bytes).unwrap(), value);
+        }
+        assert!(read_varint(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn keepalive_count() {
+        let mut metrics = Metrics::new();
+        let last = metrics.last_transfer;
+        metrics.count(10);
+        assert_eq!((metrics.bytes_transfer, metrics.packets_transfer), (10, 1));
+        assert_eq!(metrics.last_transfer, last);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        metrics.transfer(5);
+        assert_eq!(metrics.packets_transfer, 2);
+        assert!(metrics.last_transfer > last);
+    }
+
+    #[test]
+    fn link_metrics() {
+        let mut link = LinkMetrics::new();
+        link.rtt_sample(0.1);
+        assert_eq!((link.rtt, link.rtt_var), (0.1, 0.05));
+        for _ in 0..100 {
+            link.rtt_sample(0.02);
+            link.loss_sample(false);
+        }
+        assert!((link.rtt - 0.02).abs() < 0.001 && link.rtt_var < 0.001);
+        link.loss_sample(true);
+        assert!(link.loss > 0. && link.loss < 0.05);
     }
 }